@@ -1,8 +1,10 @@
-use sqlx::{Pool, Postgres};
-use transaction_state::{curry, curry2};
+use std::time::Duration;
+
+use sqlx::{Pool, Postgres, Transaction};
+use transaction_state::curry;
 use transaction_state::{
-    definitions::saga_definition::SagaDefinition,
-    persisters::persister::{LockScope, StepPersister},
+    definitions::{saga_definition::SagaDefinition, step_key::StepKey},
+    persisters::persister::{LockScope, TransactionalPersister},
 };
 use uuid::Uuid;
 
@@ -13,35 +15,71 @@ use crate::{
     states::existing_order::SagaOrderState,
 };
 
-pub fn create_definition_for_existing_order<P: StepPersister>(
+pub fn create_definition_for_existing_order<
+    P: TransactionalPersister<Transaction = Transaction<'static, Postgres>>,
+>(
     pool: Pool<Postgres>,
     persister: P,
     order_id: Uuid,
     success: bool,
     executor_id: Uuid,
 ) -> SagaDefinition<SagaOrderState, Order, TicketId, DefinitionExecutionError, P> {
-    let ticket_confirmator = TicketConfirmator {
-        pool: pool.clone(),
-        success,
-    };
-    SagaDefinition::new(
+    create_ticket_for_order(
+        pool,
+        persister,
         LockScope {
             id: order_id,
             name: "create_from_existing_order".to_string(),
             executor_id,
+            parent: None,
+            token: None,
         },
-        SagaOrderState::new,
-        (),
-        persister,
-    )
-    .step(create_ticket, SagaOrderState::create_ticket)
-    .on_error(
-        curry!(cancel_order, pool.clone()),
-        SagaOrderState::cancel_order,
-    )
-    .step(
-        curry2!(TicketConfirmator::confirm_ticket, ticket_confirmator),
-        SagaOrderState::confirm_ticket,
+        success,
     )
-    .step(curry!(send_ticket, pool), SagaOrderState::send_ticket)
+}
+
+/// Ticket sub-flow shared with the full order, which runs it as a child saga
+pub fn create_ticket_for_order<
+    P: TransactionalPersister<Transaction = Transaction<'static, Postgres>>,
+>(
+    pool: Pool<Postgres>,
+    persister: P,
+    lock_scope: LockScope,
+    success: bool,
+) -> SagaDefinition<SagaOrderState, Order, TicketId, DefinitionExecutionError, P> {
+    let ticket_confirmator = TicketConfirmator { success };
+    SagaDefinition::new(lock_scope, SagaOrderState::new, (), persister)
+        .step_with_context(create_ticket, SagaOrderState::create_ticket)
+        .name("create_ticket")
+        .on_error(
+            curry!(cancel_order, pool.clone()),
+            SagaOrderState::cancel_order,
+        )
+        .transactional_step(
+            move |(order, ticket_id), tx| async move {
+                ticket_confirmator
+                    .confirm_ticket(order, ticket_id, tx)
+                    .await
+            },
+            SagaOrderState::confirm_ticket,
+        )
+        .name("confirm_ticket")
+        .step(curry!(send_ticket, pool), SagaOrderState::send_ticket)
+        .name("send_ticket")
+        // lock timeout of the persister is 10 seconds
+        .heartbeat(Duration::from_secs(3))
+        .version(1)
+        .migration(0, |states| {
+            // version 0 persisted the steps by position
+            for (index, name) in [
+                (1, "create_ticket"),
+                (3, "confirm_ticket"),
+                (4, "send_ticket"),
+            ] {
+                if let Some(state) = states.remove(&StepKey::Index(index)) {
+                    states.insert(StepKey::Name(name.to_string()), state);
+                }
+            }
+            Ok(())
+        })
 }
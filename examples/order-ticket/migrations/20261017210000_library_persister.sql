@@ -0,0 +1,4 @@
+-- saga tables are created by PostgresPersister::migrate of the library from now on
+DROP TABLE IF EXISTS saga_step;
+DROP TABLE IF EXISTS saga_lock;
+DROP TYPE IF EXISTS lock_type;
@@ -1,4 +1,3 @@
 pub mod order;
 pub mod remote;
-pub mod sqlx_persister;
 pub mod ticket;
@@ -1,4 +1,5 @@
-use sqlx::{Pool, Postgres};
+use sqlx::{Pool, Postgres, Transaction};
+use transaction_state::definitions::step_context::StepContext;
 
 use crate::{
     models::{
@@ -12,9 +13,10 @@ use crate::{
 
 use super::remote::ExternalError;
 
-pub async fn create_ticket(order: Order) -> Result<TicketId, ExternalError> {
+/// Remote service deduplicates by the idempotency key, so a re-executed step gets the same ticket
+pub async fn create_ticket(order: Order, context: StepContext) -> Result<TicketId, ExternalError> {
     log::info!("create_ticket with order {}", order.order_id);
-    let result = execute_remote_service(order.order_id).await?;
+    let result = execute_remote_service(context.idempotency_key).await?;
     Ok(result)
 }
 
@@ -35,28 +37,29 @@ pub async fn send_ticket(pool: &Pool<Postgres>, order: Order) -> Result<EmailId,
 }
 
 pub struct TicketConfirmator {
-    pub pool: Pool<Postgres>,
     pub success: bool,
 }
 
 impl TicketConfirmator {
+    /// Runs in the transaction persisting the step, so the ticket is confirmed exactly once
     pub async fn confirm_ticket(
         &self,
         mut order: Order,
         ticket_id: TicketId,
-    ) -> Result<Order, LocalError> {
+        mut tx: Transaction<'static, Postgres>,
+    ) -> Result<(Order, Transaction<'static, Postgres>), LocalError> {
         if self.success {
             sqlx::query("UPDATE order_ticket SET ticket_id = $1 WHERE id = $2")
                 .bind(ticket_id)
                 .bind(order.order_id)
-                .execute(&self.pool)
+                .execute(&mut *tx)
                 .await
                 .map_err(|e| LocalError::Transaction(TransactionError(e.to_string())))?;
 
             log::info!("confirm_ticket {ticket_id} for order {}", order.order_id);
 
             order.ticket_id = ticket_id.into();
-            Ok(order)
+            Ok((order, tx))
         } else {
             Err(LocalError::Other("Failing confirm_ticket".to_string()))
         }
@@ -0,0 +1,85 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use serde::Serialize;
+
+use crate::persisters::persister::PersistError;
+
+pub type ItemOperation<Item, Out, E> =
+    Box<dyn Fn(Item) -> Pin<Box<dyn Future<Output = Result<Out, E>> + Send>> + Send + Sync>;
+
+/// Undoes a succeeded item, receives its output and returns the compensation output
+pub type ItemCompensation<State, Out, E> = Box<
+    dyn Fn(Arc<State>, Out) -> Pin<Box<dyn Future<Output = Result<serde_json::Value, E>> + Send>>
+        + Send,
+>;
+
+/// Operation executed for every item of a collection, results are checkpointed per item
+pub struct ForEach<State, Item, Out, WrappingError> {
+    pub(crate) operation: ItemOperation<Item, Out, WrappingError>,
+    pub(crate) concurrency: usize,
+    pub(crate) compensation: Option<ItemCompensation<State, Out, WrappingError>>,
+}
+
+impl<State, Item, Out, WrappingError> ForEach<State, Item, Out, WrappingError>
+where
+    State: Send + Sync + 'static,
+    Item: Send + 'static,
+    Out: Send + 'static,
+    WrappingError: From<PersistError> + Send + 'static,
+{
+    pub fn new<NewError, Operation, OperationFuture>(operation: Operation) -> Self
+    where
+        Operation: Fn(Item) -> OperationFuture + Send + Sync + 'static,
+        OperationFuture: Future<Output = Result<Out, NewError>> + Send + 'static,
+        WrappingError: From<NewError>,
+    {
+        Self {
+            operation: Box::new(move |item| {
+                let f = operation(item);
+                Box::pin(async move { f.await.map_err(WrappingError::from) })
+            }),
+            concurrency: 1,
+            compensation: None,
+        }
+    }
+
+    /// Maximum number of items processed at once, items are processed one by one by default
+    pub fn concurrency(mut self, limit: usize) -> Self {
+        self.concurrency = limit.max(1);
+        self
+    }
+
+    /// Compensation runs for the succeeded items only, in reverse order.
+    ///
+    /// Compensated items are checkpointed one by one, a resumed saga only compensates the rest
+    pub fn compensate<
+        NewError,
+        OperationFuture,
+        FactoryResult,
+        Factory,
+        Operation,
+        NewFutureResult,
+    >(
+        mut self,
+        operation: Operation,
+        factory: Factory,
+    ) -> Self
+    where
+        Operation: Fn(FactoryResult) -> OperationFuture + Send + 'static,
+        Factory: Fn(&State, Out) -> FactoryResult + Send + 'static,
+        OperationFuture: Future<Output = Result<NewFutureResult, NewError>> + Send + 'static,
+        WrappingError: From<NewError>,
+        NewFutureResult: Serialize + Send,
+    {
+        self.compensation = Some(Box::new(move |s, out| {
+            let compensating = operation(factory(&s, out));
+            Box::pin(async move {
+                let compensation_result = compensating.await.map_err(WrappingError::from)?;
+                serde_json::to_value(&compensation_result)
+                    .map_err(PersistError::from)
+                    .map_err(WrappingError::from)
+            })
+        }));
+        self
+    }
+}
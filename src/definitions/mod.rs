@@ -1,2 +1,9 @@
+pub mod for_each;
+pub mod parallel_branch;
+pub mod retry_policy;
 pub mod saga_definition;
-pub mod saga_state;
\ No newline at end of file
+pub mod saga_registry;
+pub mod saga_state;
+pub mod schedule;
+pub mod step_context;
+pub mod step_key;
\ No newline at end of file
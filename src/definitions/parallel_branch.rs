@@ -0,0 +1,63 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::persisters::persister::PersistError;
+
+use super::saga_definition::{compensation_definition, CompensationDefinition};
+
+pub type BranchOperation<In, Out, E> =
+    Box<dyn FnOnce(In) -> Pin<Box<dyn Future<Output = Result<Out, E>> + Send>> + Send>;
+
+/// Operation executed concurrently with other branches of a parallel step
+pub struct ParallelBranch<State, In, Out, WrappingError> {
+    pub(crate) operation: BranchOperation<In, Out, WrappingError>,
+    pub(crate) compensation: Option<CompensationDefinition<Arc<State>, WrappingError>>,
+}
+
+impl<State, In, Out, WrappingError> ParallelBranch<State, In, Out, WrappingError>
+where
+    State: 'static,
+    In: Send + 'static,
+    Out: 'static,
+    WrappingError: From<PersistError> + Send + 'static,
+{
+    pub fn new<NewError, Operation, OperationFuture>(operation: Operation) -> Self
+    where
+        Operation: FnOnce(In) -> OperationFuture + Send + 'static,
+        OperationFuture: Future<Output = Result<Out, NewError>> + Send + 'static,
+        WrappingError: From<NewError>,
+    {
+        Self {
+            operation: Box::new(move |data| {
+                Box::pin(async move { operation(data).await.map_err(WrappingError::from) })
+            }),
+            compensation: None,
+        }
+    }
+
+    /// Compensation runs only if this branch completed
+    pub fn compensate<
+        NewError,
+        OperationFuture,
+        FactoryResult: Send + 'static,
+        Factory,
+        Operation,
+        NewFutureResult,
+    >(
+        mut self,
+        operation: Operation,
+        factory: Factory,
+    ) -> Self
+    where
+        Operation: FnOnce(FactoryResult) -> OperationFuture + Send + 'static,
+        Factory: FnOnce(&State, Out) -> FactoryResult + Send + 'static,
+        OperationFuture: Future<Output = Result<NewFutureResult, NewError>> + Send + 'static,
+        Out: DeserializeOwned,
+        WrappingError: From<NewError>,
+        NewFutureResult: Serialize,
+    {
+        self.compensation = Some(compensation_definition(operation, factory));
+        self
+    }
+}
@@ -0,0 +1,123 @@
+use std::{sync::Arc, time::Duration};
+
+use rand::Rng;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Backoff {
+    Fixed(Duration),
+    /// Doubles the delay after every attempt until max is reached
+    Exponential {
+        initial: Duration,
+        max: Duration,
+    },
+}
+
+pub type RetryPredicate<E> = Arc<dyn Fn(&E) -> bool + Send + Sync>;
+
+pub struct RetryPolicy<E> {
+    max_attempts: u32,
+    backoff: Backoff,
+    jitter: Duration,
+    retry_on: Option<RetryPredicate<E>>,
+}
+
+impl<E> RetryPolicy<E> {
+    pub fn new(max_attempts: u32, backoff: Backoff) -> Self {
+        Self {
+            max_attempts,
+            backoff,
+            jitter: Duration::ZERO,
+            retry_on: None,
+        }
+    }
+
+    pub fn fixed(max_attempts: u32, delay: Duration) -> Self {
+        Self::new(max_attempts, Backoff::Fixed(delay))
+    }
+
+    pub fn exponential(max_attempts: u32, initial: Duration, max: Duration) -> Self {
+        Self::new(max_attempts, Backoff::Exponential { initial, max })
+    }
+
+    /// Adds a random delay up to jitter to every backoff
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Only errors matching the predicate are retried
+    pub fn retry_on(mut self, predicate: impl Fn(&E) -> bool + Send + Sync + 'static) -> Self {
+        self.retry_on = Some(Arc::new(predicate));
+        self
+    }
+
+    pub fn should_retry(&self, attempt: u32, error: &E) -> bool {
+        attempt < self.max_attempts
+            && self
+                .retry_on
+                .as_ref()
+                .map(|predicate| predicate(error))
+                .unwrap_or(true)
+    }
+
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => initial
+                .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+                .min(max),
+        };
+        if self.jitter.is_zero() {
+            delay
+        } else {
+            delay + rand::thread_rng().gen_range(Duration::ZERO..=self.jitter)
+        }
+    }
+}
+
+impl<E> Clone for RetryPolicy<E> {
+    fn clone(&self) -> Self {
+        Self {
+            max_attempts: self.max_attempts,
+            backoff: self.backoff,
+            jitter: self.jitter,
+            retry_on: self.retry_on.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_delay() {
+        let policy: RetryPolicy<()> =
+            RetryPolicy::exponential(10, Duration::from_millis(10), Duration::from_millis(50));
+        assert_eq!(Duration::from_millis(10), policy.delay(1));
+        assert_eq!(Duration::from_millis(20), policy.delay(2));
+        assert_eq!(Duration::from_millis(40), policy.delay(3));
+        assert_eq!(Duration::from_millis(50), policy.delay(4));
+        assert_eq!(Duration::from_millis(50), policy.delay(40));
+    }
+
+    #[test]
+    fn test_jitter_delay() {
+        let policy: RetryPolicy<()> =
+            RetryPolicy::fixed(10, Duration::from_millis(10)).with_jitter(Duration::from_millis(5));
+        for attempt in 1..10 {
+            let delay = policy.delay(attempt);
+            assert!(delay >= Duration::from_millis(10), "{delay:?}");
+            assert!(delay <= Duration::from_millis(15), "{delay:?}");
+        }
+    }
+
+    #[test]
+    fn test_should_retry() {
+        let policy = RetryPolicy::fixed(3, Duration::ZERO).retry_on(|e: &&str| *e == "transient");
+        assert!(policy.should_retry(1, &"transient"));
+        assert!(policy.should_retry(2, &"transient"));
+        assert!(!policy.should_retry(3, &"transient"));
+        assert!(!policy.should_retry(1, &"permanent"));
+    }
+}
@@ -1,16 +1,33 @@
 use std::{
+    collections::BTreeMap,
     error::Error,
     future::Future,
     pin::Pin,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime},
 };
 
 use async_trait::async_trait;
+use futures_util::{future, stream, FutureExt, StreamExt};
 use serde::{de::DeserializeOwned, Serialize};
+use tokio::time::{sleep, timeout};
+use uuid::Uuid;
 
-use crate::persisters::persister::{LockScope, LockType, PersistError, StepPersister};
+use crate::persisters::persister::{
+    LockScope, LockType, PersistError, StepPersister, TransactionalPersister,
+};
 
-use super::saga_state::SagaState;
+use super::{
+    for_each::{ForEach, ItemCompensation, ItemOperation},
+    parallel_branch::ParallelBranch,
+    retry_policy::RetryPolicy,
+    saga_state::SagaState,
+    step_context::StepContext,
+    step_key::StepKey,
+};
 
 #[async_trait]
 pub trait SagaRunner<In, Out, WrappingError> {
@@ -23,19 +40,128 @@ pub trait SagaRunner<In, Out, WrappingError> {
 pub type OperationDefinition<State, In, Out, E> =
     Box<dyn FnOnce(In) -> (State, Pin<Box<dyn Future<Output = Result<Out, E>> + Send>>) + Send>;
 
+/// Undoes a completed step, receives the persisted output of the step it compensates
+/// and returns its own serialized output
+pub type CompensationDefinition<State, E> = Box<
+    dyn FnOnce(State, String) -> Pin<Box<dyn Future<Output = Result<String, E>> + Send>> + Send,
+>;
+
+/// Looks up the outcome of a step started by an earlier execution and returns its serialized
+/// output if the step happened
+pub type Verification<E> = Box<
+    dyn FnOnce(StepContext) -> Pin<Box<dyn Future<Output = Result<Option<String>, E>> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// What happens with the saga once a step timeout or the saga deadline expires
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutOutcome {
+    /// Saga is marked as failed without compensating and can be retried later.
+    /// A saga past its deadline can not succeed anymore, it ends as `LockType::Cancelled`
+    Fail,
+    /// Compensations of completed steps are executed
+    Compensate,
+}
+
+/// Upgrades persisted step payloads of a saga to the next definition version
+pub type Migration =
+    Box<dyn Fn(&mut BTreeMap<StepKey, String>) -> Result<(), PersistError> + Send + Sync>;
+
+#[derive(Default)]
+struct DefinitionOptions {
+    deadline: Option<(Duration, TimeoutOutcome)>,
+    heartbeat: Option<Duration>,
+    version: u32,
+    migrations: BTreeMap<u32, Migration>,
+    steps: BTreeMap<u32, StepOptions>,
+}
+
+#[derive(Default)]
+struct StepOptions {
+    timeout: Option<(Duration, TimeoutOutcome)>,
+    name: Option<String>,
+    // part of a step combining several persisted steps, keyed by the combining step and position
+    part_of: Option<(u32, u32)>,
+}
+
+/// Reason the saga stopped without compensating
+#[derive(Debug, Clone, Copy)]
+enum Interruption {
+    Failed,
+    /// Saga deadline passed, the saga stops for good
+    Expired,
+    /// Another executor took over the saga
+    LockLost,
+    /// Saga was paused from outside
+    Paused,
+    /// Saga waits for a signal
+    Waiting,
+    /// Saga sleeps until the time
+    Sleeping(SystemTime),
+}
+
+// saga id with the fencing token of the lock this executor holds, shared by all steps
+#[derive(Clone)]
+struct Fence {
+    id: Uuid,
+    token: Arc<RwLock<Option<u64>>>,
+}
+
+impl Fence {
+    fn new(id: Uuid) -> Self {
+        Self {
+            id,
+            token: Default::default(),
+        }
+    }
+
+    // writes before the first lock carry no valid token and are rejected
+    fn token(&self) -> u64 {
+        self.token.read().expect("fence").unwrap_or_default()
+    }
+
+    fn scope(&self, lock_scope: &LockScope) -> LockScope {
+        LockScope {
+            token: *self.token.read().expect("fence"),
+            ..lock_scope.clone()
+        }
+    }
+
+    async fn lock<Persister: StepPersister>(
+        &self,
+        lock_scope: &LockScope,
+        lock_type: LockType,
+        persister: &Persister,
+    ) -> Result<(), PersistError> {
+        let token = persister.lock(self.scope(lock_scope), lock_type).await?;
+        *self.token.write().expect("fence") = Some(token);
+        Ok(())
+    }
+}
+
+/// Chain of steps executed by `SagaDefinition::branch`, receives the shared state and the branch input
+pub type BranchDefinition<State, In, Out, WrappingError, Persister> =
+    SagaDefinition<State, (Arc<State>, In), Out, WrappingError, Persister>;
+
 pub struct SagaDefinition<State, In, Out, WrappingError, Persister> {
     lock_scope: LockScope,
-    step: u8,
+    step: u32,
     operation: OperationDefinition<Arc<State>, In, Out, WrappingError>,
+    compensations: Vec<(u32, CompensationDefinition<Arc<State>, WrappingError>)>,
     persister: Persister,
     existing_saga: Arc<RwLock<SagaState>>,
+    options: Arc<RwLock<DefinitionOptions>>,
+    interruption: Arc<RwLock<Option<Interruption>>>,
+    fence: Fence,
+    verifications: Arc<RwLock<BTreeMap<u32, Verification<WrappingError>>>>,
 }
 
 impl<State, FactoryData, OperationResult, WrappingError, Persister>
     SagaDefinition<State, FactoryData, OperationResult, WrappingError, Persister>
 where
     State: 'static + Send + Sync,
-    FactoryData: Serialize + 'static + Send + Sync,
+    FactoryData: 'static + Send,
     OperationResult: 'static + Send,
     WrappingError: Error + From<PersistError> + 'static + Send + Sync,
     Persister: StepPersister + Clone + Send + Sync + 'static,
@@ -47,15 +173,17 @@ where
         persister: Persister,
     ) -> Self
     where
+        FactoryData: Serialize + Sync,
         StateCreator: FnOnce(FactoryData, &OperationResult) -> State + Send + 'static,
     {
         let existing_saga = Arc::new(RwLock::new(SagaState::new(lock_scope.id)));
-        let step = 0;
+        let step = StepKey::Index(0);
         let persist = persister.clone();
-        let scope_id = lock_scope.id;
+        let fence = Fence::new(lock_scope.id);
         Self {
             lock_scope,
-            step,
+            fence: fence.clone(),
+            step: 0,
             existing_saga: existing_saga.clone(),
             operation: Box::new(move |fd| {
                 let initial_state = serde_json::to_string(&fd)
@@ -71,17 +199,27 @@ where
                         .states
                         .contains_key(&step)
                     {
+                        let initial_state = initial_state?;
                         persist
-                            .store(scope_id, step, initial_state?)
+                            .store(fence.id, fence.token(), step.clone(), initial_state.clone())
                             .await
                             .map_err(WrappingError::from)?;
+                        existing_saga
+                            .write()
+                            .expect("existing saga")
+                            .states
+                            .insert(step, initial_state);
                     }
 
                     Ok(initial_data)
                 });
                 (Arc::new(s), f)
             }),
+            compensations: Vec::new(),
             persister,
+            options: Default::default(),
+            interruption: Default::default(),
+            verifications: Default::default(),
         }
     }
 
@@ -91,7 +229,7 @@ where
         FactoryResult: Send + 'static,
         Factory,
         Operation,
-        NewFutureResult: 'static,
+        NewFutureResult,
     >(
         self,
         operation: Operation,
@@ -102,13 +240,652 @@ where
         Factory: FnOnce(&State, OperationResult) -> FactoryResult + Send + 'static,
         OperationFuture: Future<Output = Result<NewFutureResult, NewError>> + Send + 'static,
         WrappingError: From<NewError> + From<PersistError>,
-        NewFutureResult: Serialize + DeserializeOwned + Send + Sync,
+        NewFutureResult: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        self.add_step(factory, move |factory_result| async move {
+            operation(factory_result).await.map_err(WrappingError::from)
+        })
+    }
+
+    /// Same as `step`, but the operation also receives the context of the step.
+    ///
+    /// Pass its idempotency key to external services, so a step re-executed after a crash
+    /// between the side effect and persisting the result does not repeat the side effect.
+    pub fn step_with_context<
+        NewError,
+        OperationFuture,
+        FactoryResult: Send + 'static,
+        Factory,
+        Operation,
+        NewFutureResult,
+    >(
+        self,
+        operation: Operation,
+        factory: Factory,
+    ) -> SagaDefinition<State, FactoryData, NewFutureResult, WrappingError, Persister>
+    where
+        Operation: FnOnce(FactoryResult, StepContext) -> OperationFuture + Send + 'static,
+        Factory: FnOnce(&State, OperationResult) -> FactoryResult + Send + 'static,
+        OperationFuture: Future<Output = Result<NewFutureResult, NewError>> + Send + 'static,
+        WrappingError: From<NewError> + From<PersistError>,
+        NewFutureResult: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let definition_step = self.step + 1;
+        let id = self.lock_scope.id;
+        let options = self.options.clone();
+        self.add_step(factory, move |factory_result| async move {
+            let context = StepContext::new(id, step_key(definition_step, &options));
+            operation(factory_result, context)
+                .await
+                .map_err(WrappingError::from)
+        })
+    }
+
+    /// Same as `step`, but the operation runs in a transaction of the persister which also
+    /// persists the step result, so a local database step takes effect exactly once.
+    ///
+    /// The operation hands the transaction back with its output, a failed operation drops it
+    /// and so rolls it back.
+    pub fn transactional_step<
+        NewError,
+        OperationFuture,
+        FactoryResult: Send + 'static,
+        Factory,
+        Operation,
+        NewFutureResult,
+    >(
+        self,
+        operation: Operation,
+        factory: Factory,
+    ) -> SagaDefinition<State, FactoryData, NewFutureResult, WrappingError, Persister>
+    where
+        Persister: TransactionalPersister,
+        Operation:
+            FnOnce(FactoryResult, Persister::Transaction) -> OperationFuture + Send + 'static,
+        Factory: FnOnce(&State, OperationResult) -> FactoryResult + Send + 'static,
+        OperationFuture: Future<Output = Result<(NewFutureResult, Persister::Transaction), NewError>>
+            + Send
+            + 'static,
+        WrappingError: From<NewError> + From<PersistError>,
+        NewFutureResult: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let persister = self.persister.clone();
+        let definition_step = self.step + 1;
+        let existing_saga = self.existing_saga.clone();
+        let fence = self.fence.clone();
+        let options = self.options.clone();
+        self.add_step(factory, move |factory_result| async move {
+            let key = step_key(definition_step, &options);
+            let transaction = persister
+                .begin(fence.id, fence.token())
+                .await
+                .map_err(WrappingError::from)?;
+            let (result, transaction) = operation(factory_result, transaction)
+                .await
+                .map_err(WrappingError::from)?;
+            let state = serde_json::to_string(&result)
+                .map_err(PersistError::from)
+                .map_err(WrappingError::from)?;
+            persister
+                .commit_step(transaction, fence.id, key.clone(), state.clone())
+                .await
+                .map_err(WrappingError::from)?;
+            existing_saga
+                .write()
+                .expect("existing saga")
+                .states
+                .insert(key, state);
+            Ok(result)
+        })
+    }
+
+    /// Same as `step`, but the operation is retried in process according to the policy.
+    ///
+    /// Attempts are persisted, so an executor taking over the saga continues counting.
+    /// A resumed saga always gets at least one attempt.
+    pub fn step_with_retry<
+        NewError,
+        OperationFuture,
+        FactoryResult: Clone + Send + 'static,
+        Factory,
+        Operation,
+        NewFutureResult,
+    >(
+        self,
+        operation: Operation,
+        factory: Factory,
+        policy: RetryPolicy<NewError>,
+    ) -> SagaDefinition<State, FactoryData, NewFutureResult, WrappingError, Persister>
+    where
+        Operation: Fn(FactoryResult) -> OperationFuture + Send + 'static,
+        Factory: FnOnce(&State, OperationResult) -> FactoryResult + Send + 'static,
+        OperationFuture: Future<Output = Result<NewFutureResult, NewError>> + Send + 'static,
+        WrappingError: From<NewError> + From<PersistError>,
+        NewFutureResult: Serialize + DeserializeOwned + Send + Sync + 'static,
+        NewError: Send + 'static,
+    {
+        let persister = self.persister.clone();
+        let definition_step = self.step + 1;
+        let existing_saga = self.existing_saga.clone();
+        let fence = self.fence.clone();
+        let options = self.options.clone();
+        self.add_step(factory, move |factory_result| async move {
+            let key = step_key(definition_step, &options);
+            let mut attempt = existing_saga
+                .read()
+                .expect("existing saga")
+                .attempts
+                .get(&key)
+                .copied()
+                .unwrap_or_default();
+            loop {
+                attempt += 1;
+                persister
+                    .store_attempt(fence.id, fence.token(), key.clone(), attempt)
+                    .await
+                    .map_err(WrappingError::from)?;
+                existing_saga
+                    .write()
+                    .expect("existing saga")
+                    .attempts
+                    .insert(key.clone(), attempt);
+
+                match operation(factory_result.clone()).await {
+                    Ok(r) => return Ok(r),
+                    Err(e) if !policy.should_retry(attempt, &e) => {
+                        return Err(WrappingError::from(e))
+                    }
+                    Err(_) => {
+                        let delay = policy.delay(attempt);
+                        log::trace!("retrying step {key} attempt {attempt} in {delay:?}");
+                        sleep(delay).await;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Executes two branches concurrently and joins their results.
+    ///
+    /// Every branch is checkpointed separately, so a resumed saga only executes unfinished branches.
+    pub fn parallel<InA, A, InB, B, Factory>(
+        mut self,
+        branch_a: ParallelBranch<State, InA, A, WrappingError>,
+        branch_b: ParallelBranch<State, InB, B, WrappingError>,
+        factory: Factory,
+    ) -> SagaDefinition<State, FactoryData, (A, B), WrappingError, Persister>
+    where
+        Factory: FnOnce(&State, OperationResult) -> (InA, InB) + Send + 'static,
+        InA: Send + 'static,
+        InB: Send + 'static,
+        A: Serialize + DeserializeOwned + Send + Sync + 'static,
+        B: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let step_a = self.add_branch(branch_a.compensation);
+        let step_b = self.add_branch(branch_b.compensation);
+        self.join_branches(&[step_a, step_b]);
+        let persister = self.persister.clone();
+        let existing_saga = self.existing_saga.clone();
+        let fence = self.fence.clone();
+        let options = self.options.clone();
+        self.add_step(factory, move |(in_a, in_b)| async move {
+            let (key_a, key_b) = (step_key(step_a, &options), step_key(step_b, &options));
+            let (a, b) = tokio::join!(
+                checkpoint(&fence, key_a, &existing_saga, &persister, || {
+                    (branch_a.operation)(in_a)
+                }),
+                checkpoint(&fence, key_b, &existing_saga, &persister, || {
+                    (branch_b.operation)(in_b)
+                }),
+            );
+            Ok((a?, b?))
+        })
+    }
+
+    /// Same as `parallel` for three branches
+    pub fn parallel3<InA, A, InB, B, InC, C, Factory>(
+        mut self,
+        branch_a: ParallelBranch<State, InA, A, WrappingError>,
+        branch_b: ParallelBranch<State, InB, B, WrappingError>,
+        branch_c: ParallelBranch<State, InC, C, WrappingError>,
+        factory: Factory,
+    ) -> SagaDefinition<State, FactoryData, (A, B, C), WrappingError, Persister>
+    where
+        Factory: FnOnce(&State, OperationResult) -> (InA, InB, InC) + Send + 'static,
+        InA: Send + 'static,
+        InB: Send + 'static,
+        InC: Send + 'static,
+        A: Serialize + DeserializeOwned + Send + Sync + 'static,
+        B: Serialize + DeserializeOwned + Send + Sync + 'static,
+        C: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let step_a = self.add_branch(branch_a.compensation);
+        let step_b = self.add_branch(branch_b.compensation);
+        let step_c = self.add_branch(branch_c.compensation);
+        self.join_branches(&[step_a, step_b, step_c]);
+        let persister = self.persister.clone();
+        let existing_saga = self.existing_saga.clone();
+        let fence = self.fence.clone();
+        let options = self.options.clone();
+        self.add_step(factory, move |(in_a, in_b, in_c)| async move {
+            let (key_a, key_b, key_c) = (
+                step_key(step_a, &options),
+                step_key(step_b, &options),
+                step_key(step_c, &options),
+            );
+            let (a, b, c) = tokio::join!(
+                checkpoint(&fence, key_a, &existing_saga, &persister, || {
+                    (branch_a.operation)(in_a)
+                }),
+                checkpoint(&fence, key_b, &existing_saga, &persister, || {
+                    (branch_b.operation)(in_b)
+                }),
+                checkpoint(&fence, key_c, &existing_saga, &persister, || {
+                    (branch_c.operation)(in_c)
+                }),
+            );
+            Ok((a?, b?, c?))
+        })
+    }
+
+    /// Executes the operation for every item produced by the factory.
+    ///
+    /// Progress of the items is checkpointed in its own step, a resumed saga only
+    /// executes the items which did not succeed yet. Results keep the order of the items.
+    pub fn for_each<Item, Out, Factory>(
+        mut self,
+        for_each: ForEach<State, Item, Out, WrappingError>,
+        factory: Factory,
+    ) -> SagaDefinition<State, FactoryData, Vec<Out>, WrappingError, Persister>
+    where
+        Factory: FnOnce(&State, OperationResult) -> Vec<Item> + Send + 'static,
+        Item: Send + 'static,
+        Out: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let progress_step = self.add_branch(None);
+        self.join_branches(&[progress_step]);
+        if let Some(compensation) = for_each.compensation {
+            let persister = self.persister.clone();
+            let existing_saga = self.existing_saga.clone();
+            let fence = self.fence.clone();
+            let options = self.options.clone();
+            self.compensations.push((
+                progress_step,
+                Box::new(move |s, completed| {
+                    Box::pin(async move {
+                        compensate_items(
+                            &fence,
+                            step_key(progress_step, &options),
+                            s,
+                            completed,
+                            compensation,
+                            &existing_saga,
+                            &persister,
+                        )
+                        .await
+                    })
+                }),
+            ));
+        }
+        let persister = self.persister.clone();
+        let existing_saga = self.existing_saga.clone();
+        let fence = self.fence.clone();
+        let options = self.options.clone();
+        self.add_step(factory, move |items| async move {
+            execute_items(
+                &fence,
+                step_key(progress_step, &options),
+                items,
+                for_each.operation,
+                for_each.concurrency,
+                &existing_saga,
+                &persister,
+            )
+            .await
+        })
+    }
+
+    /// Executes one of two step chains depending on the predicate.
+    ///
+    /// Chosen chain is persisted, so a resumed saga continues with the same chain
+    /// even if the predicate would evaluate differently.
+    pub fn branch<NewFutureResult, Predicate, IfTrue, IfFalse>(
+        self,
+        predicate: Predicate,
+        if_true: IfTrue,
+        if_false: IfFalse,
+    ) -> SagaDefinition<State, FactoryData, NewFutureResult, WrappingError, Persister>
+    where
+        Predicate: FnOnce(&State, &OperationResult) -> bool + Send + 'static,
+        IfTrue: FnOnce(
+            BranchDefinition<State, OperationResult, OperationResult, WrappingError, Persister>,
+        ) -> BranchDefinition<
+            State,
+            OperationResult,
+            NewFutureResult,
+            WrappingError,
+            Persister,
+        >,
+        IfFalse: FnOnce(
+            BranchDefinition<State, OperationResult, OperationResult, WrappingError, Persister>,
+        ) -> BranchDefinition<
+            State,
+            OperationResult,
+            NewFutureResult,
+            WrappingError,
+            Persister,
+        >,
+        NewFutureResult: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let branch_step = self.step + 1;
+        let true_chain = if_true(self.branch_definition(branch_step));
+        let false_chain = if_false(self.branch_definition(true_chain.step));
+        self.options
+            .write()
+            .expect("definition options")
+            .steps
+            .entry(branch_step)
+            .or_default()
+            .part_of = Some((false_chain.step + 1, 0));
+
+        let previous = self.operation;
+        let persister = self.persister.clone();
+        let existing_saga = self.existing_saga.clone();
+        let fence = self.fence.clone();
+        let options = self.options.clone();
+        let true_operation = true_chain.operation;
+        let false_operation = false_chain.operation;
+        let mut compensations = self.compensations;
+        compensations.extend(true_chain.compensations);
+        compensations.extend(false_chain.compensations);
+
+        SagaDefinition {
+            lock_scope: self.lock_scope,
+            step: false_chain.step,
+            existing_saga: self.existing_saga,
+            operation: Box::new(move |d| {
+                let (current_state, previous_executing) = previous(d);
+
+                let s = current_state.clone();
+                let f = Box::pin(async move {
+                    log::trace!("executing branch {branch_step}");
+                    let operation_result = previous_executing.await?;
+                    // persisted choice takes precedence over the evaluated one
+                    let evaluated = predicate(&s, &operation_result);
+                    let chosen = checkpoint(
+                        &fence,
+                        step_key(branch_step, &options),
+                        &existing_saga,
+                        &persister,
+                        || async move { Ok(evaluated) },
+                    )
+                    .await?;
+                    let chain = if chosen {
+                        true_operation
+                    } else {
+                        false_operation
+                    };
+                    let (_, f) = chain((s, operation_result));
+                    f.await
+                });
+                (current_state, f)
+            }),
+            compensations,
+            persister: self.persister,
+            options: self.options,
+            interruption: self.interruption,
+            fence: self.fence,
+            verifications: self.verifications,
+        }
+        // joining step allows to compensate or limit the branch as a whole
+        .add_step(|_, r| r, |r| async move { Ok(r) })
+    }
+
+    /// Executes another saga as a single step of this one.
+    ///
+    /// Child runs under its own lock scope derived from this saga and keeps its own checkpoints.
+    /// A child left unfinished stops this saga without compensation, so resuming this saga
+    /// resumes the child. A waiting, sleeping or paused child parks this saga the same way,
+    /// a signal delivered to the child wakes this saga up. A child past its deadline stops this
+    /// saga for good. A cancelled child cancels this saga as well.
+    /// A completed child is undone with the compensation of this step.
+    pub fn child<ChildState, ChildData, ChildResult, ChildError, Child, Factory>(
+        self,
+        child: Child,
+        factory: Factory,
+    ) -> SagaDefinition<State, FactoryData, ChildResult, WrappingError, Persister>
+    where
+        Child: FnOnce(
+                LockScope,
+                Persister,
+            )
+                -> SagaDefinition<ChildState, ChildData, ChildResult, ChildError, Persister>
+            + Send
+            + 'static,
+        Factory: FnOnce(&State, OperationResult) -> ChildData + Send + 'static,
+        ChildState: Send + Sync + 'static,
+        ChildData: Send + 'static,
+        ChildResult: Serialize + DeserializeOwned + Send + Sync + 'static,
+        ChildError: From<PersistError> + Send + 'static,
+        WrappingError: From<ChildError>,
+    {
+        let definition_step = self.step + 1;
+        let lock_scope = self.lock_scope.clone();
+        let persister = self.persister.clone();
+        let existing_saga = self.existing_saga.clone();
+        let options = self.options.clone();
+        let interruption = self.interruption.clone();
+        self.add_step(factory, move |data| async move {
+            // scope is derived from the key, so a renamed step gets the same child
+            let scope = lock_scope.child(&step_key(definition_step, &options));
+            let child = child(scope, persister);
+            let child_interruption = child.interruption.clone();
+            let (result, finished) = child.run_as_child(data).await;
+            match (&result, finished) {
+                (Ok(_), _) => {}
+                // child was cancelled or compensated, this saga cannot continue either
+                (Err(_), true) => existing_saga.write().expect("existing saga").cancelled = true,
+                (Err(_), false) => {
+                    let stopped = match *child_interruption.read().expect("interruption") {
+                        Some(
+                            i @ (Interruption::Paused
+                            | Interruption::Waiting
+                            | Interruption::Sleeping(_)
+                            | Interruption::Expired),
+                        ) => i,
+                        _ => Interruption::Failed,
+                    };
+                    *interruption.write().expect("interruption") = Some(stopped)
+                }
+            }
+            result.map_err(WrappingError::from)
+        })
+    }
+
+    /// Parks the saga until `StepPersister::signal` delivers a payload with the name.
+    ///
+    /// The saga releases its lock as `LockType::Waiting` and is resumed once signalled.
+    /// The payload is the output of this step and is passed to the next factory.
+    /// The signal is consumed with the step, waiting for the name again needs a new delivery.
+    pub fn wait_for_signal<Payload>(
+        self,
+        name: impl Into<String>,
+    ) -> SagaDefinition<State, FactoryData, Payload, WrappingError, Persister>
+    where
+        Payload: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let persister = self.persister.clone();
+        let definition_step = self.step + 1;
+        let existing_saga = self.existing_saga.clone();
+        let fence = self.fence.clone();
+        let options = self.options.clone();
+        let interruption = self.interruption.clone();
+        self.add_step(
+            |_, _| (),
+            move |_| async move {
+                let delivered = existing_saga
+                    .read()
+                    .expect("existing saga")
+                    .signals
+                    .get(&name)
+                    .cloned();
+                let payload = match delivered {
+                    Some(payload) => Some(payload),
+                    // signal might have arrived after the saga was loaded
+                    None => match persister.retrieve(fence.id).await {
+                        Ok(mut saga) => saga.signals.remove(&name),
+                        Err(PersistError::NotFound) => None,
+                        Err(e) => return Err(WrappingError::from(e)),
+                    },
+                };
+                match payload {
+                    Some(payload) => {
+                        let key = step_key(definition_step, &options);
+                        let payload: Payload = serde_json::from_str(&payload)
+                            .map_err(PersistError::from)
+                            .map_err(WrappingError::from)?;
+                        let state = serde_json::to_string(&payload)
+                            .map_err(PersistError::from)
+                            .map_err(WrappingError::from)?;
+                        persister
+                            .store_consumed_signal(
+                                fence.id,
+                                fence.token(),
+                                key.clone(),
+                                state.clone(),
+                                &name,
+                            )
+                            .await
+                            .map_err(WrappingError::from)?;
+                        let mut saga = existing_saga.write().expect("existing saga");
+                        saga.states.insert(key, state);
+                        saga.signals.remove(&name);
+                        Ok(payload)
+                    }
+                    None => {
+                        log::trace!("saga {} waits for signal {name}", fence.id);
+                        *interruption.write().expect("interruption") = Some(Interruption::Waiting);
+                        Err(WrappingError::from(PersistError::Waiting))
+                    }
+                }
+            },
+        )
+    }
+
+    /// Parks the saga for the duration, measured from the first execution of this step.
+    ///
+    /// The saga releases its lock as `LockType::Sleeping` and is resumed once the time passed.
+    /// The output of the previous step is passed through to the next factory.
+    pub fn sleep(self, duration: Duration) -> Self
+    where
+        OperationResult: Serialize + DeserializeOwned + Sync,
+    {
+        self.add_timer(move || SystemTime::now() + duration)
+    }
+
+    /// Same as `sleep`, but parks the saga until the time
+    pub fn sleep_until(self, wake_at: SystemTime) -> Self
+    where
+        OperationResult: Serialize + DeserializeOwned + Sync,
+    {
+        self.add_timer(move || wake_at)
+    }
+
+    // wake up time is checkpointed in its own step, so a resumed saga keeps the original time
+    fn add_timer<WakeAt>(mut self, wake_at: WakeAt) -> Self
+    where
+        WakeAt: FnOnce() -> SystemTime + Send + 'static,
+        OperationResult: Serialize + DeserializeOwned + Sync,
+    {
+        let timer_step = self.add_branch(None);
+        self.join_branches(&[timer_step]);
+        let persister = self.persister.clone();
+        let existing_saga = self.existing_saga.clone();
+        let fence = self.fence.clone();
+        let options = self.options.clone();
+        let interruption = self.interruption.clone();
+        self.add_step(
+            |_, r| r,
+            move |r| async move {
+                let wake_at: SystemTime = checkpoint(
+                    &fence,
+                    step_key(timer_step, &options),
+                    &existing_saga,
+                    &persister,
+                    || async move { Ok(wake_at()) },
+                )
+                .await?;
+                if SystemTime::now() < wake_at {
+                    log::trace!("saga {} sleeps until {wake_at:?}", fence.id);
+                    *interruption.write().expect("interruption") =
+                        Some(Interruption::Sleeping(wake_at));
+                    return Err(WrappingError::from(PersistError::Sleeping));
+                }
+                Ok(r)
+            },
+        )
+    }
+
+    fn branch_definition(
+        &self,
+        step: u32,
+    ) -> BranchDefinition<State, OperationResult, OperationResult, WrappingError, Persister> {
+        SagaDefinition {
+            lock_scope: self.lock_scope.clone(),
+            step,
+            existing_saga: self.existing_saga.clone(),
+            operation: Box::new(|(s, d)| (s, Box::pin(async move { Ok(d) }))),
+            compensations: Vec::new(),
+            persister: self.persister.clone(),
+            options: self.options.clone(),
+            interruption: self.interruption.clone(),
+            fence: self.fence.clone(),
+            verifications: self.verifications.clone(),
+        }
+    }
+
+    // reserves a step for a parallel branch
+    fn add_branch(
+        &mut self,
+        compensation: Option<CompensationDefinition<Arc<State>, WrappingError>>,
+    ) -> u32 {
+        self.step += 1;
+        if let Some(compensation) = compensation {
+            self.compensations.push((self.step, compensation));
+        }
+        self.step
+    }
+
+    // reserved steps are keyed by the name of the step joining them, if it gets one
+    fn join_branches(&self, steps: &[u32]) {
+        let mut options = self.options.write().expect("definition options");
+        for (part, step) in (0..).zip(steps) {
+            options.steps.entry(*step).or_default().part_of = Some((self.step + 1, part));
+        }
+    }
+
+    fn add_step<FactoryResult, Factory, NewFutureResult, Execute, ExecuteFuture>(
+        self,
+        factory: Factory,
+        execute: Execute,
+    ) -> SagaDefinition<State, FactoryData, NewFutureResult, WrappingError, Persister>
+    where
+        FactoryResult: Send + 'static,
+        Factory: FnOnce(&State, OperationResult) -> FactoryResult + Send + 'static,
+        Execute: FnOnce(FactoryResult) -> ExecuteFuture + Send + 'static,
+        ExecuteFuture: Future<Output = Result<NewFutureResult, WrappingError>> + Send + 'static,
+        NewFutureResult: Serialize + DeserializeOwned + Send + Sync + 'static,
     {
         let previous = self.operation;
         let persister = self.persister.clone();
         let definition_step = self.step + 1;
         let existing_saga = self.existing_saga.clone();
-        let scope_id = self.lock_scope.id;
+        let fence = self.fence.clone();
+        let lock_scope = self.lock_scope.clone();
+        let options = self.options.clone();
+        let interruption = self.interruption.clone();
+        let verifications = self.verifications.clone();
         SagaDefinition {
             lock_scope: self.lock_scope,
             step: definition_step,
@@ -121,38 +898,62 @@ where
                     log::trace!("executing step {definition_step}");
                     let operation_result = previous_executing.await?;
                     let factory_result = factory(&s, operation_result);
-                    let existing_state = {
-                        existing_saga
-                            .read()
-                            .expect("existing saga")
-                            .states
-                            .get(&definition_step)
-                            .map(|s| serde_json::from_str(s))
-                    };
-
-                    if let Some(new_operation_result) = existing_state {
-                        new_operation_result
-                            .map_err(PersistError::from)
-                            .map_err(WrappingError::from)
-                    } else {
-                        let new_operation_result =
-                            operation(factory_result).await.map_err(WrappingError::from);
-
-                        if let Ok(r) = &new_operation_result {
-                            let state = serde_json::to_string(r)
-                                .map_err(PersistError::from)
-                                .map_err(WrappingError::from)?;
+                    let key = step_key(definition_step, &options);
+                    checkpoint(&fence, key.clone(), &existing_saga, &persister, || async {
+                        observe_interruptions(
+                            &lock_scope,
+                            &existing_saga,
+                            &interruption,
+                            &persister,
+                        )
+                        .await?;
+                        let verification = verifications
+                            .write()
+                            .expect("verifications")
+                            .remove(&definition_step);
+                        let verified = verification.is_some();
+                        if let Some(result) = verify_started(
+                            &fence,
+                            &key,
+                            verification,
+                            &existing_saga,
+                            &interruption,
+                        )
+                        .await?
+                        {
+                            return Ok(result);
+                        }
+                        // only a verification makes use of the marker, other steps skip the write
+                        if verified {
                             persister
-                                .store(scope_id, definition_step, state)
+                                .store_started(fence.id, fence.token(), key.clone())
                                 .await
                                 .map_err(WrappingError::from)?;
+                            existing_saga
+                                .write()
+                                .expect("existing saga")
+                                .started
+                                .insert(key.clone());
                         }
-                        new_operation_result
-                    }
+                        execute_with_timeout(
+                            definition_step,
+                            execute(factory_result),
+                            &options,
+                            &existing_saga,
+                            &interruption,
+                        )
+                        .await
+                    })
+                    .await
                 });
                 (current_state, f)
             }),
+            compensations: self.compensations,
             persister: self.persister,
+            options: self.options,
+            interruption: self.interruption,
+            fence: self.fence,
+            verifications: self.verifications,
         }
     }
 
@@ -203,105 +1004,938 @@ where
                 });
                 (current_state, f)
             }),
+            compensations: self.compensations,
             persister: self.persister,
+            options: self.options,
+            interruption: self.interruption,
+            fence: self.fence,
+            verifications: self.verifications,
         }
     }
 
-    pub fn lock_scope(&self) -> &LockScope {
-        &self.lock_scope
+    /// Attaches a compensation to the previous step replacing any existing one.
+    ///
+    /// If any later step fails, compensations of all completed steps run in reverse order.
+    /// Each compensation receives the persisted output of the step it undoes.
+    /// Compensation outputs are persisted, so a resumed saga only runs the remaining ones.
+    pub fn compensate<
+        NewError,
+        OperationFuture,
+        FactoryResult: Send + 'static,
+        Factory,
+        Operation,
+        NewFutureResult,
+    >(
+        mut self,
+        operation: Operation,
+        factory: Factory,
+    ) -> Self
+    where
+        Operation: FnOnce(FactoryResult) -> OperationFuture + Send + 'static,
+        Factory: FnOnce(&State, OperationResult) -> FactoryResult + Send + 'static,
+        OperationFuture: Future<Output = Result<NewFutureResult, NewError>> + Send + 'static,
+        OperationResult: DeserializeOwned,
+        WrappingError: From<NewError> + From<PersistError>,
+        NewFutureResult: Serialize,
+    {
+        assert!(self.step > 0, "initial data can not be compensated");
+        let definition_step = self.step;
+        self.compensations
+            .retain(|(step, _)| *step != definition_step);
+        self.compensations
+            .push((definition_step, compensation_definition(operation, factory)));
+        self
     }
-}
 
-#[async_trait]
-impl<State, FactoryData, OperationResult, WrappingError, Persister>
-    SagaRunner<FactoryData, OperationResult, WrappingError>
-    for SagaDefinition<State, FactoryData, OperationResult, WrappingError, Persister>
-where
-    FactoryData: Send,
-    OperationResult: Send,
-    WrappingError: From<PersistError> + Send,
-    Persister: StepPersister + Clone + Send + Sync + 'static,
-{
-    async fn run(self, data: FactoryData) -> Result<OperationResult, WrappingError> {
-        let lock_scope = self.lock_scope;
-        self.persister
-            .lock(lock_scope.clone(), LockType::Executing)
-            .await
-            .map_err(WrappingError::from)?;
-        let saga_result = self.persister.retrieve(lock_scope.id).await;
-        if let Ok(s) = saga_result {
-            *self.existing_saga.write().expect("saga lock") = s;
-        }
+    /// Limits how long the previous step operation including its retries may run
+    pub fn timeout(self, duration: Duration, outcome: TimeoutOutcome) -> Self {
+        assert!(self.step > 0, "initial data can not time out");
+        self.options
+            .write()
+            .expect("definition options")
+            .steps
+            .entry(self.step)
+            .or_default()
+            .timeout = Some((duration, outcome));
+        self
+    }
 
-        let (_, f) = (self.operation)(data);
-        let result = f.await;
+    /// Verifies the outcome of the previous step when a resumed saga finds it started but
+    /// unfinished, because the executor crashed before persisting its result.
+    ///
+    /// Returning the output of a step which happened persists it instead of executing the step
+    /// again, returning none executes it again. A failed verification stops the saga without
+    /// compensating, so it is retried later.
+    pub fn verify<NewError, VerifyFuture, Verify>(self, verify: Verify) -> Self
+    where
+        Verify: FnOnce(StepContext) -> VerifyFuture + Send + Sync + 'static,
+        VerifyFuture: Future<Output = Result<Option<OperationResult>, NewError>> + Send + 'static,
+        OperationResult: Serialize,
+        WrappingError: From<NewError>,
+    {
+        assert!(self.step > 0, "initial data can not be verified");
+        self.verifications.write().expect("verifications").insert(
+            self.step,
+            Box::new(move |context| {
+                Box::pin(async move {
+                    verify(context)
+                        .await
+                        .map_err(WrappingError::from)?
+                        .map(|r| serde_json::to_string(&r))
+                        .transpose()
+                        .map_err(PersistError::from)
+                        .map_err(WrappingError::from)
+                })
+            }),
+        );
+        self
+    }
 
-        let finish = result.is_ok() || self.existing_saga.read().expect("saga lock").cancelled;
+    /// Persists the previous step under the name instead of its position.
+    ///
+    /// Named steps keep their persisted results when steps are added or removed before them,
+    /// so in-flight sagas survive such changes. Steps combined by `parallel` and `for_each`
+    /// are keyed by the name as well.
+    pub fn name(self, name: impl Into<String>) -> Self {
+        assert!(self.step > 0, "initial data can not be named");
+        let name = name.into();
+        assert!(
+            name.parse::<u32>().is_err(),
+            "step name {name} would collide with step indexes"
+        );
+        let mut options = self.options.write().expect("definition options");
+        assert!(
+            !options
+                .steps
+                .values()
+                .any(|o| o.name.as_ref() == Some(&name)),
+            "step name {name} is used already"
+        );
+        options.steps.entry(self.step).or_default().name = Some(name);
+        drop(options);
+        self
+    }
 
-        self.persister
-            .lock(
-                lock_scope,
-                if finish {
-                    LockType::Finished
-                } else {
-                    LockType::Failed
-                },
-            )
-            .await
-            .map_err(WrappingError::from)?;
-        result
+    /// Version persisted with every saga started by this definition, 0 by default.
+    ///
+    /// Sagas persisted by another version are only resumed if migrations lead to this version.
+    pub fn version(self, version: u32) -> Self {
+        self.options.write().expect("definition options").version = version;
+        self
+    }
+
+    /// Upgrades step payloads persisted by version `from` to version `from + 1`.
+    ///
+    /// Migrations run in sequence before an older saga is resumed and the result is persisted.
+    pub fn migration(
+        self,
+        from: u32,
+        migration: impl Fn(&mut BTreeMap<StepKey, String>) -> Result<(), PersistError>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.options
+            .write()
+            .expect("definition options")
+            .migrations
+            .insert(from, Box::new(migration));
+        self
+    }
+
+    /// Renews the lock of the saga with the interval while it executes.
+    ///
+    /// Interval must be shorter than the lock timeout of the persister. Once the lock is lost
+    /// to another executor the saga stops without compensating.
+    pub fn heartbeat(self, interval: Duration) -> Self {
+        self.options.write().expect("definition options").heartbeat = Some(interval);
+        self
+    }
+
+    /// Limits how long the whole saga may run.
+    ///
+    /// Deadline is persisted when the saga starts, resumed sagas honour the original deadline.
+    pub fn deadline(self, duration: Duration, outcome: TimeoutOutcome) -> Self {
+        self.options.write().expect("definition options").deadline = Some((duration, outcome));
+        self
+    }
+
+    pub fn lock_scope(&self) -> &LockScope {
+        &self.lock_scope
+    }
+}
+
+#[async_trait]
+impl<State, FactoryData, OperationResult, WrappingError, Persister>
+    SagaRunner<FactoryData, OperationResult, WrappingError>
+    for SagaDefinition<State, FactoryData, OperationResult, WrappingError, Persister>
+where
+    State: Send + Sync + 'static,
+    FactoryData: Send,
+    OperationResult: Send,
+    WrappingError: From<PersistError> + Send,
+    Persister: StepPersister + Clone + Send + Sync + 'static,
+{
+    async fn run(self, data: FactoryData) -> Result<OperationResult, WrappingError> {
+        self.start().await?.execute(data).await.0
     }
 
     async fn continue_from_last_step(self) -> Result<OperationResult, WrappingError>
     where
         FactoryData: DeserializeOwned,
     {
-        let lock_scope = self.lock_scope;
         let saga = {
-            self.persister
-                .lock(lock_scope.clone(), LockType::Executing)
+            self.fence
+                .lock(&self.lock_scope, LockType::Executing, &self.persister)
                 .await
                 .map_err(WrappingError::from)?;
-            self.persister
-                .retrieve(lock_scope.id)
+            let saga = self
+                .persister
+                .retrieve(self.lock_scope.id)
                 .await
-                .map_err(WrappingError::from)?
+                .map_err(WrappingError::from)?;
+            upgrade(
+                saga,
+                &self.lock_scope,
+                &self.fence,
+                &self.options,
+                &self.persister,
+            )
+            .await
+            .map_err(WrappingError::from)?
         };
 
-        let state = saga.states.get(&0).ok_or(PersistError::NotFound)?;
+        let state = saga
+            .states
+            .get(&StepKey::Index(0))
+            .ok_or(PersistError::NotFound)?;
         let data = serde_json::from_str(state)
             .map_err(PersistError::from)
             .map_err(WrappingError::from)?;
         *self.existing_saga.write().expect("saga lock") = saga;
 
-        let (_, f) = (self.operation)(data);
-        let result = f.await;
+        self.execute(data).await.0
+    }
+}
+
+impl<State, FactoryData, OperationResult, WrappingError, Persister>
+    SagaDefinition<State, FactoryData, OperationResult, WrappingError, Persister>
+where
+    State: Send + Sync + 'static,
+    WrappingError: From<PersistError>,
+    Persister: StepPersister,
+{
+    async fn start(self) -> Result<Self, WrappingError> {
+        self.fence
+            .lock(&self.lock_scope, LockType::Executing, &self.persister)
+            .await
+            .map_err(WrappingError::from)?;
+        let saga_result = self.persister.retrieve(self.lock_scope.id).await;
+        if let Ok(s) = saga_result {
+            let s = upgrade(
+                s,
+                &self.lock_scope,
+                &self.fence,
+                &self.options,
+                &self.persister,
+            )
+            .await
+            .map_err(WrappingError::from)?;
+            *self.existing_saga.write().expect("saga lock") = s;
+        }
+        Ok(self)
+    }
 
-        let finish = result.is_ok() || self.existing_saga.read().expect("saga lock").cancelled;
+    // whether the saga parked waiting, sleeping or paused once it ran
+    pub(crate) fn parked(&self) -> impl Fn() -> bool + Send + 'static {
+        let interruption = self.interruption.clone();
+        move || {
+            matches!(
+                *interruption.read().expect("interruption"),
+                Some(Interruption::Paused | Interruption::Waiting | Interruption::Sleeping(_))
+            )
+        }
+    }
 
-        self.persister
-            .lock(
-                lock_scope,
-                if finish {
-                    LockType::Finished
-                } else {
-                    LockType::Failed
-                },
+    async fn run_as_child(
+        self,
+        data: FactoryData,
+    ) -> (Result<OperationResult, WrappingError>, bool) {
+        match self.start().await {
+            Ok(definition) => definition.execute(data).await,
+            Err(e) => (Err(e), false),
+        }
+    }
+
+    // returns the result together with whether the saga finished
+    async fn execute(self, data: FactoryData) -> (Result<OperationResult, WrappingError>, bool) {
+        let (state, f) = (self.operation)(data);
+        let compensating = {
+            let saga = self.existing_saga.read().expect("saga lock");
+            saga.cancelled || saga.cancel_requested
+        };
+        let result = if compensating {
+            // forward steps must not run again once the saga started compensating
+            log::trace!("continue compensating {}", self.lock_scope.id);
+            Err(WrappingError::from(PersistError::Cancelled))
+        } else {
+            match persist_start(
+                &self.fence,
+                &self.existing_saga,
+                &self.options,
+                &self.persister,
+            )
+            .await
+            {
+                Ok(_) => {
+                    with_heartbeat(
+                        f,
+                        &self.fence.scope(&self.lock_scope),
+                        &self.options,
+                        &self.persister,
+                        &self.interruption,
+                    )
+                    .await
+                }
+                Err(e) => Err(WrappingError::from(e)),
+            }
+        };
+
+        let interruption = *self.interruption.read().expect("interruption");
+        let (result, finish) = match (result, interruption) {
+            (Ok(r), _) => (Ok(r), true),
+            (Err(e), Some(Interruption::LockLost)) => return (Err(e), false),
+            (Err(e), Some(_)) => (Err(e), false),
+            (Err(e), None) => match with_heartbeat(
+                compensate(
+                    &self.fence,
+                    state,
+                    self.compensations,
+                    &self.existing_saga,
+                    &self.options,
+                    &self.persister,
+                ),
+                &self.fence.scope(&self.lock_scope),
+                &self.options,
+                &self.persister,
+                &self.interruption,
+            )
+            .await
+            {
+                Ok(_) => {
+                    let saga = self.existing_saga.read().expect("saga lock");
+                    (Err(e), saga.cancelled || saga.cancel_requested)
+                }
+                Err(compensation_error) => (Err(compensation_error), false),
+            },
+        };
+        if matches!(
+            *self.interruption.read().expect("interruption"),
+            Some(Interruption::LockLost)
+        ) {
+            // the saga belongs to another executor now
+            return (result, false);
+        }
+
+        let cancel_requested = self
+            .existing_saga
+            .read()
+            .expect("saga lock")
+            .cancel_requested;
+        let lock_type = match (finish, interruption) {
+            (true, _) if cancel_requested => LockType::Cancelled,
+            (true, _) => LockType::Finished,
+            (false, Some(Interruption::Paused)) => LockType::Paused,
+            (false, Some(Interruption::Waiting)) => LockType::Waiting,
+            (false, Some(Interruption::Sleeping(wake_at))) => LockType::Sleeping(wake_at),
+            // retrying cannot finish a saga past its deadline
+            (false, Some(Interruption::Expired)) => LockType::Cancelled,
+            (false, _) => LockType::Failed,
+        };
+        match self
+            .fence
+            .lock(&self.lock_scope, lock_type, &self.persister)
+            .await
+        {
+            Ok(_) => (result, finish),
+            Err(e) => {
+                // the saga did not park, whatever it waited for
+                *self.interruption.write().expect("interruption") = Some(Interruption::Failed);
+                (Err(WrappingError::from(e)), false)
+            }
+        }
+    }
+}
+
+pub(crate) fn compensation_definition<
+    State,
+    StepResult,
+    WrappingError,
+    NewError,
+    OperationFuture,
+    FactoryResult,
+    Factory,
+    Operation,
+    NewFutureResult,
+>(
+    operation: Operation,
+    factory: Factory,
+) -> CompensationDefinition<Arc<State>, WrappingError>
+where
+    Operation: FnOnce(FactoryResult) -> OperationFuture + Send + 'static,
+    Factory: FnOnce(&State, StepResult) -> FactoryResult + Send + 'static,
+    OperationFuture: Future<Output = Result<NewFutureResult, NewError>> + Send + 'static,
+    FactoryResult: Send + 'static,
+    StepResult: DeserializeOwned,
+    WrappingError: From<NewError> + From<PersistError> + Send + 'static,
+    NewFutureResult: Serialize,
+{
+    Box::new(move |s, state| {
+        let operation_result = serde_json::from_str(&state)
+            .map_err(PersistError::from)
+            .map_err(WrappingError::from);
+        let factory_result = operation_result.map(|r| factory(&s, r));
+        Box::pin(async move {
+            let compensation_result = operation(factory_result?)
+                .await
+                .map_err(WrappingError::from)?;
+            serde_json::to_string(&compensation_result)
+                .map_err(PersistError::from)
+                .map_err(WrappingError::from)
+        })
+    })
+}
+
+// renews the lock while the operation runs, the operation is abandoned once the lock is lost
+async fn with_heartbeat<T, WrappingError, Persister>(
+    operation: impl Future<Output = Result<T, WrappingError>>,
+    lock_scope: &LockScope,
+    options: &RwLock<DefinitionOptions>,
+    persister: &Persister,
+    interruption: &RwLock<Option<Interruption>>,
+) -> Result<T, WrappingError>
+where
+    WrappingError: From<PersistError>,
+    Persister: StepPersister,
+{
+    let heartbeat = options.read().expect("definition options").heartbeat;
+    let Some(interval) = heartbeat else {
+        return operation.await;
+    };
+
+    let renewing = async {
+        loop {
+            sleep(interval).await;
+            match persister.renew(lock_scope.clone()).await {
+                Ok(_) => {}
+                Err(PersistError::LockLost) => return PersistError::LockLost,
+                // lock is still valid until it times out, next renewal may succeed
+                Err(e) => log::warn!("failed to renew lock of {}: {e}", lock_scope.id),
+            }
+        }
+    };
+
+    tokio::select! {
+        result = operation => result,
+        lost = renewing => {
+            log::warn!("lost lock of {}, stopping", lock_scope.id);
+            *interruption.write().expect("interruption") = Some(Interruption::LockLost);
+            Err(WrappingError::from(lost))
+        }
+    }
+}
+
+// persists what a saga needs to be resumed consistently, once per saga
+async fn persist_start<Persister: StepPersister>(
+    fence: &Fence,
+    existing_saga: &RwLock<SagaState>,
+    options: &RwLock<DefinitionOptions>,
+    persister: &Persister,
+) -> Result<(), PersistError> {
+    let (version, deadline) = {
+        let saga = existing_saga.read().expect("existing saga");
+        let options = options.read().expect("definition options");
+        (
+            saga.version.is_none().then_some(options.version),
+            options
+                .deadline
+                .filter(|_| saga.deadline.is_none())
+                .map(|(duration, _)| SystemTime::now() + duration),
+        )
+    };
+    if let Some(version) = version {
+        persister
+            .store_version(fence.id, fence.token(), version)
+            .await?;
+        existing_saga.write().expect("existing saga").version = Some(version);
+    }
+    if let Some(deadline) = deadline {
+        persister
+            .store_deadline(fence.id, fence.token(), deadline)
+            .await?;
+        existing_saga.write().expect("existing saga").deadline = Some(deadline);
+    }
+    Ok(())
+}
+
+// migrates a saga persisted by an older definition version, sagas without version are version 0.
+// Incompatible sagas are released for an executor with a matching definition
+async fn upgrade<Persister: StepPersister>(
+    saga: SagaState,
+    lock_scope: &LockScope,
+    fence: &Fence,
+    options: &RwLock<DefinitionOptions>,
+    persister: &Persister,
+) -> Result<SagaState, PersistError> {
+    let stored = saga.version.unwrap_or_default();
+    let migrated = {
+        let options = options.read().expect("definition options");
+        if stored == options.version {
+            return Ok(saga);
+        }
+        let mut states = saga.states.clone();
+        let migrated = (stored..options.version)
+            .try_for_each(|from| match options.migrations.get(&from) {
+                Some(migration) => migration(&mut states),
+                None => Err(PersistError::IncompatibleVersion(stored, options.version)),
+            })
+            .map(|_| (options.version, states));
+        if stored > options.version {
+            Err(PersistError::IncompatibleVersion(stored, options.version))
+        } else {
+            migrated
+        }
+    };
+
+    match migrated {
+        Ok((version, states)) => {
+            log::trace!(
+                "migrated saga {} from version {stored} to {version}",
+                saga.id
+            );
+            persister
+                .store_migration(fence.id, fence.token(), version, states.clone())
+                .await?;
+            Ok(SagaState {
+                states,
+                version: Some(version),
+                ..saga
+            })
+        }
+        Err(e) => {
+            fence.lock(lock_scope, LockType::Failed, persister).await?;
+            Err(e)
+        }
+    }
+}
+
+// cancellation requested from outside is observed before a step executes, it takes precedence
+// over pausing, which stops the saga without compensating
+async fn observe_interruptions<WrappingError, Persister>(
+    lock_scope: &LockScope,
+    existing_saga: &RwLock<SagaState>,
+    interruption: &RwLock<Option<Interruption>>,
+    persister: &Persister,
+) -> Result<(), WrappingError>
+where
+    WrappingError: From<PersistError>,
+    Persister: StepPersister,
+{
+    let (cancel_requested, paused) = persister
+        .requested_interruptions(lock_scope.id, &lock_scope.name)
+        .await
+        .map_err(WrappingError::from)?;
+    if cancel_requested {
+        log::trace!("saga {} was cancelled", lock_scope.id);
+        existing_saga
+            .write()
+            .expect("existing saga")
+            .cancel_requested = true;
+        return Err(WrappingError::from(PersistError::Cancelled));
+    }
+    if paused {
+        log::trace!("saga {} is paused", lock_scope.id);
+        *interruption.write().expect("interruption") = Some(Interruption::Paused);
+        return Err(WrappingError::from(PersistError::Paused));
+    }
+    Ok(())
+}
+
+// named steps keep their key when steps are added or removed before them
+fn step_key(step: u32, options: &RwLock<DefinitionOptions>) -> StepKey {
+    let options = options.read().expect("definition options");
+    let name = |step| options.steps.get(&step).and_then(|o| o.name.clone());
+    let part_of = options.steps.get(&step).and_then(|o| o.part_of);
+    match (name(step), part_of) {
+        (Some(name), _) => StepKey::Name(name),
+        (None, Some((joining, part))) => name(joining)
+            .map(|name| StepKey::Name(format!("{name}/{part}")))
+            .unwrap_or(StepKey::Index(step)),
+        (None, None) => StepKey::Index(step),
+    }
+}
+
+/// Returns the output of a step started by an earlier execution if its verification found it,
+/// steps without a verification are executed again
+async fn verify_started<T, WrappingError>(
+    fence: &Fence,
+    step: &StepKey,
+    verification: Option<Verification<WrappingError>>,
+    existing_saga: &RwLock<SagaState>,
+    interruption: &RwLock<Option<Interruption>>,
+) -> Result<Option<T>, WrappingError>
+where
+    T: DeserializeOwned,
+    WrappingError: From<PersistError>,
+{
+    if !existing_saga
+        .read()
+        .expect("existing saga")
+        .started
+        .contains(step)
+    {
+        return Ok(None);
+    }
+    let Some(verification) = verification else {
+        log::trace!("executing started step {step} again");
+        return Ok(None);
+    };
+
+    match verification(StepContext::new(fence.id, step.clone())).await {
+        Ok(Some(state)) => serde_json::from_str(&state)
+            .map(Some)
+            .map_err(PersistError::from)
+            .map_err(WrappingError::from),
+        Ok(None) => Ok(None),
+        Err(e) => {
+            // outcome is unknown, compensating might undo a step which did not happen
+            *interruption.write().expect("interruption") = Some(Interruption::Failed);
+            Err(e)
+        }
+    }
+}
+
+/// Returns persisted step result or executes the operation and persists its result
+async fn checkpoint<T, WrappingError, Persister, Operation, OperationFuture>(
+    fence: &Fence,
+    step: StepKey,
+    existing_saga: &RwLock<SagaState>,
+    persister: &Persister,
+    operation: Operation,
+) -> Result<T, WrappingError>
+where
+    T: Serialize + DeserializeOwned,
+    Operation: FnOnce() -> OperationFuture,
+    OperationFuture: Future<Output = Result<T, WrappingError>>,
+    WrappingError: From<PersistError>,
+    Persister: StepPersister,
+{
+    let existing_state = {
+        existing_saga
+            .read()
+            .expect("existing saga")
+            .states
+            .get(&step)
+            .map(|s| serde_json::from_str(s))
+    };
+
+    if let Some(operation_result) = existing_state {
+        return operation_result
+            .map_err(PersistError::from)
+            .map_err(WrappingError::from);
+    }
+
+    let operation_result = operation().await?;
+    let state = serde_json::to_string(&operation_result)
+        .map_err(PersistError::from)
+        .map_err(WrappingError::from)?;
+    // transactional steps persist their result with the operation
+    let persisted = existing_saga
+        .read()
+        .expect("existing saga")
+        .states
+        .get(&step)
+        .is_some_and(|s| *s == state);
+    if persisted {
+        return Ok(operation_result);
+    }
+    persister
+        .store(fence.id, fence.token(), step.clone(), state.clone())
+        .await
+        .map_err(WrappingError::from)?;
+    existing_saga
+        .write()
+        .expect("existing saga")
+        .states
+        .insert(step, state);
+    Ok(operation_result)
+}
+
+// stores results of succeeded items after every item
+async fn execute_items<Item, Out, WrappingError, Persister>(
+    fence: &Fence,
+    step: StepKey,
+    items: Vec<Item>,
+    operation: ItemOperation<Item, Out, WrappingError>,
+    concurrency: usize,
+    existing_saga: &RwLock<SagaState>,
+    persister: &Persister,
+) -> Result<Vec<Out>, WrappingError>
+where
+    Out: Serialize + DeserializeOwned,
+    WrappingError: From<PersistError>,
+    Persister: StepPersister,
+{
+    let existing_state = {
+        existing_saga
+            .read()
+            .expect("existing saga")
+            .states
+            .get(&step)
+            .map(|s| serde_json::from_str::<BTreeMap<usize, Out>>(s))
+    };
+    let mut completed = existing_state
+        .transpose()
+        .map_err(PersistError::from)
+        .map_err(WrappingError::from)?
+        .unwrap_or_default();
+    let pending: Vec<_> = items
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !completed.contains_key(index))
+        .collect();
+
+    // after a failure no new items are started, items already running are still checkpointed
+    let failed = AtomicBool::new(false);
+    let mut results = stream::iter(pending)
+        .take_while(|_| future::ready(!failed.load(Ordering::SeqCst)))
+        .map(|(index, item)| operation(item).map(move |result| (index, result)))
+        .buffer_unordered(concurrency);
+    let mut failure = None;
+    while let Some((index, result)) = results.next().await {
+        match result {
+            Ok(out) => {
+                log::trace!("item {index} of step {step} succeeded");
+                completed.insert(index, out);
+                let state = serde_json::to_string(&completed)
+                    .map_err(PersistError::from)
+                    .map_err(WrappingError::from)?;
+                persister
+                    .store(fence.id, fence.token(), step.clone(), state.clone())
+                    .await
+                    .map_err(WrappingError::from)?;
+                existing_saga
+                    .write()
+                    .expect("existing saga")
+                    .states
+                    .insert(step.clone(), state);
+            }
+            Err(e) => {
+                failed.store(true, Ordering::SeqCst);
+                failure.get_or_insert(e);
+            }
+        }
+    }
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(completed.into_values().collect()),
+    }
+}
+
+// compensated items are checkpointed under their own key, the step itself counts as
+// compensated only once all of its items are
+async fn compensate_items<State, Out, WrappingError, Persister>(
+    fence: &Fence,
+    step: StepKey,
+    state: Arc<State>,
+    completed: String,
+    compensation: ItemCompensation<State, Out, WrappingError>,
+    existing_saga: &RwLock<SagaState>,
+    persister: &Persister,
+) -> Result<String, WrappingError>
+where
+    Out: DeserializeOwned,
+    WrappingError: From<PersistError>,
+    Persister: StepPersister,
+{
+    let progress_step = StepKey::Name(format!("{step}/compensation"));
+    let completed = serde_json::from_str::<BTreeMap<usize, Out>>(&completed)
+        .map_err(PersistError::from)
+        .map_err(WrappingError::from)?;
+    let existing_progress = {
+        existing_saga
+            .read()
+            .expect("existing saga")
+            .compensations
+            .get(&progress_step)
+            .map(|s| serde_json::from_str::<BTreeMap<usize, serde_json::Value>>(s))
+    };
+    let mut compensated = existing_progress
+        .transpose()
+        .map_err(PersistError::from)
+        .map_err(WrappingError::from)?
+        .unwrap_or_default();
+
+    for (index, out) in completed.into_iter().rev() {
+        if compensated.contains_key(&index) {
+            continue;
+        }
+        log::trace!("compensating item {index} of step {step}");
+        let compensation_result = compensation(state.clone(), out).await?;
+        compensated.insert(index, compensation_result);
+        let progress = serde_json::to_string(&compensated)
+            .map_err(PersistError::from)
+            .map_err(WrappingError::from)?;
+        persister
+            .store_compensation(
+                fence.id,
+                fence.token(),
+                progress_step.clone(),
+                progress.clone(),
+            )
+            .await
+            .map_err(WrappingError::from)?;
+        existing_saga
+            .write()
+            .expect("existing saga")
+            .compensations
+            .insert(progress_step.clone(), progress);
+    }
+
+    // results in the order the items were compensated
+    let compensation_results: Vec<_> = compensated.into_values().rev().collect();
+    serde_json::to_string(&compensation_results)
+        .map_err(PersistError::from)
+        .map_err(WrappingError::from)
+}
+
+async fn execute_with_timeout<T, WrappingError>(
+    step: u32,
+    operation: impl Future<Output = Result<T, WrappingError>>,
+    options: &RwLock<DefinitionOptions>,
+    existing_saga: &RwLock<SagaState>,
+    interruption: &RwLock<Option<Interruption>>,
+) -> Result<T, WrappingError>
+where
+    WrappingError: From<PersistError>,
+{
+    // the flag tells the saga deadline from the step timeout
+    let limit = {
+        let options = options.read().expect("definition options");
+        let step_timeout = options
+            .steps
+            .get(&step)
+            .and_then(|o| o.timeout)
+            .map(|(duration, outcome)| (duration, outcome, false));
+        let saga_timeout = existing_saga
+            .read()
+            .expect("existing saga")
+            .deadline
+            .zip(options.deadline)
+            .map(|(deadline, (_, outcome))| {
+                (
+                    deadline
+                        .duration_since(SystemTime::now())
+                        .unwrap_or(Duration::ZERO),
+                    outcome,
+                    true,
+                )
+            });
+        step_timeout
+            .into_iter()
+            .chain(saga_timeout)
+            .min_by_key(|(duration, _, _)| *duration)
+    };
+
+    let Some((duration, outcome, deadline)) = limit else {
+        return operation.await;
+    };
+
+    let result = if duration.is_zero() {
+        None
+    } else {
+        timeout(duration, operation).await.ok()
+    };
+
+    result.unwrap_or_else(|| {
+        log::trace!("step {step} timed out after {duration:?}");
+        if outcome == TimeoutOutcome::Fail {
+            *interruption.write().expect("interruption") = Some(if deadline {
+                Interruption::Expired
+            } else {
+                Interruption::Failed
+            });
+        }
+        Err(WrappingError::from(PersistError::Timeout))
+    })
+}
+
+// if a compensation fails saga will continue compensating from the last compensated step
+async fn compensate<State, WrappingError, Persister>(
+    fence: &Fence,
+    state: Arc<State>,
+    compensations: Vec<(u32, CompensationDefinition<Arc<State>, WrappingError>)>,
+    existing_saga: &RwLock<SagaState>,
+    options: &RwLock<DefinitionOptions>,
+    persister: &Persister,
+) -> Result<(), WrappingError>
+where
+    WrappingError: From<PersistError>,
+    Persister: StepPersister,
+{
+    let pending: Vec<_> = {
+        let saga = existing_saga.read().expect("existing saga");
+        compensations
+            .into_iter()
+            .rev()
+            .map(|(step, compensation)| (step_key(step, options), compensation))
+            .filter(|(step, _)| !saga.compensations.contains_key(step))
+            .filter_map(|(step, compensation)| {
+                saga.states
+                    .get(&step)
+                    .map(|completed| (step, compensation, completed.clone()))
+            })
+            .collect()
+    };
+
+    // on_error might have marked saga as cancelled without persisting it
+    if !pending.is_empty() {
+        persister
+            .store_cancelled(fence.id, fence.token())
+            .await
+            .map_err(WrappingError::from)?;
+        existing_saga.write().expect("existing saga").cancelled = true;
+    }
+
+    for (step, compensation, completed) in pending {
+        log::trace!("compensating step {step}");
+        let compensation_state = compensation(state.clone(), completed).await?;
+        persister
+            .store_compensation(
+                fence.id,
+                fence.token(),
+                step.clone(),
+                compensation_state.clone(),
             )
             .await
             .map_err(WrappingError::from)?;
-        result
+        existing_saga
+            .write()
+            .expect("existing saga")
+            .compensations
+            .insert(step, compensation_state);
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fmt::Display, time::Duration};
-
-    use uuid::Uuid;
+    use std::{fmt::Display, sync::atomic::AtomicU32, time::Duration};
 
     use crate::{
-        persisters::{blackhole::Blackhole, in_memory::InMemoryPersister},
+        definitions::{for_each::ForEach, parallel_branch::ParallelBranch},
+        persisters::{blackhole::Blackhole, in_memory::InMemoryPersister, persister::SagaSelector},
         {curry, curry2},
     };
 
@@ -485,50 +2119,453 @@ mod tests {
             )
     }
 
-    fn create_definition_with_error(
+    #[derive(Default)]
+    struct Compensator {
+        undone: RwLock<Vec<String>>,
+        failing: Option<&'static str>,
+    }
+
+    impl Compensator {
+        async fn undo(&self, step: String) -> Result<(), DefinitionError> {
+            if matches!(self.failing, Some(f) if step.starts_with(f)) {
+                return Err(DefinitionError(step));
+            }
+            self.undone.write().unwrap().push(step);
+            Ok(())
+        }
+    }
+
+    fn create_definition_with_compensation<P: StepPersister>(
+        definition_id: Uuid,
         success: bool,
-    ) -> SagaDefinition<State, String, Option<char>, DefinitionError, Blackhole> {
-        let lock_scope = LockScope::from_id(Uuid::new_v4(), "create_definition2".to_string());
-        SagaDefinition::new(lock_scope, State::new, 3, Blackhole::default())
-            .step(curry!(produce_error, success), State::for_test1)
-            .on_error(stop_on_error, State::handle_produce_error)
+        compensator: Arc<Compensator>,
+        p: P,
+    ) -> SagaDefinition<State, String, u32, DefinitionError, P> {
+        let lock_scope = LockScope::from_id(
+            definition_id,
+            "create_definition_with_compensation".to_string(),
+        );
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .compensate(curry!(Compensator::undo, compensator.clone()), |_, r| {
+                format!("test1 {r}")
+            })
             .step(test2, State::for_test2)
+            .compensate(curry!(Compensator::undo, compensator.clone()), |_, r| {
+                format!("test2 {r:?}")
+            })
+            .step(move |(a, b)| test3(a, b || success), State::for_test3)
+            .step(test4, State::for_test4)
+            .compensate(curry!(Compensator::undo, compensator), |_, r| {
+                format!("test4 {r}")
+            })
     }
 
-    #[tokio::test]
-    async fn test_definition1() {
-        let definition = create_definition1();
-        let run_with_data = "run data".to_string();
-        let result = definition.run(run_with_data.clone()).await.unwrap();
-        assert_eq!(0, result);
+    #[derive(Default)]
+    struct Flaky {
+        failures: u32,
+        calls: AtomicU32,
     }
 
-    #[tokio::test]
-    async fn test_definition2() {
-        let definition = create_definition2();
-        let run_with_data = "run data".to_string();
-        let result = definition.run(run_with_data.clone()).await.unwrap();
-        assert_eq!(Some('t'), result);
+    impl Flaky {
+        async fn call(&self, v: usize) -> Result<bool, DefinitionError> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err(DefinitionError("flaky".to_string()))
+            } else {
+                Ok(v > 10)
+            }
+        }
     }
 
-    #[tokio::test]
-    async fn test_definition3() {
-        let definition_id = Uuid::new_v4();
-        let definition = create_definition3(definition_id, 1, false, Blackhole::default());
-        let run_with_data = "run data".to_string();
-        let result = definition.run(run_with_data.clone()).await;
-        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+    fn create_definition_with_retry<P: StepPersister>(
+        definition_id: Uuid,
+        success: bool,
+        flaky: Arc<Flaky>,
+        p: P,
+    ) -> SagaDefinition<State, String, u32, DefinitionError, P> {
+        let lock_scope =
+            LockScope::from_id(definition_id, "create_definition_with_retry".to_string());
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step_with_retry(
+                move |v| {
+                    let flaky = flaky.clone();
+                    async move { flaky.call(v).await }
+                },
+                State::for_test1,
+                RetryPolicy::fixed(3, Duration::from_millis(1)),
+            )
+            .step(test2, State::for_test2)
+            .step(move |(a, b)| test3(a, b || success), State::for_test3)
+            .step(test4, State::for_test4)
+    }
 
-        let definition = create_definition3(definition_id, 6, false, Blackhole::default());
-        let run_with_data = "run data".to_string();
-        let result = definition.run(run_with_data.clone()).await.unwrap();
-        assert_eq!(13, result);
+    async fn slow(v: bool) -> Result<bool, DefinitionError> {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok(v)
     }
 
-    #[tokio::test]
-    async fn test_definition3_continue() {
-        let definition_id = Uuid::new_v4();
-        let persister = InMemoryPersister::new(Duration::from_millis(5));
+    fn create_definition_with_timeout<P: StepPersister>(
+        definition_id: Uuid,
+        outcome: TimeoutOutcome,
+        compensator: Arc<Compensator>,
+        p: P,
+    ) -> SagaDefinition<State, String, Option<char>, DefinitionError, P> {
+        let lock_scope =
+            LockScope::from_id(definition_id, "create_definition_with_timeout".to_string());
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .compensate(curry!(Compensator::undo, compensator), |_, r| {
+                format!("test1 {r}")
+            })
+            .step(slow, |_, r| r)
+            .timeout(Duration::from_millis(5), outcome)
+            .step(test2, State::for_test2)
+    }
+
+    fn create_definition_with_parallel<P: StepPersister>(
+        definition_id: Uuid,
+        flaky: Arc<Flaky>,
+        counter: Arc<Flaky>,
+        compensator: Arc<Compensator>,
+        p: P,
+    ) -> SagaDefinition<State, String, (bool, bool), DefinitionError, P> {
+        let lock_scope =
+            LockScope::from_id(definition_id, "create_definition_with_parallel".to_string());
+        SagaDefinition::new(lock_scope, State::new, 1, p).parallel(
+            ParallelBranch::new(curry!(Flaky::call, counter))
+                .compensate(curry!(Compensator::undo, compensator.clone()), |_, r| {
+                    format!("counter {r}")
+                }),
+            ParallelBranch::new(curry!(Flaky::call, flaky))
+                .compensate(curry!(Compensator::undo, compensator), |_, r| {
+                    format!("flaky {r}")
+                }),
+            |s, initial| (s.for_test1(initial), 11),
+        )
+    }
+
+    fn create_definition_with_branch<P: StepPersister>(
+        definition_id: Uuid,
+        choose: bool,
+        success: bool,
+        p: P,
+    ) -> SagaDefinition<State, String, u32, DefinitionError, P> {
+        let lock_scope =
+            LockScope::from_id(definition_id, "create_definition_with_branch".to_string());
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .branch(
+                move |_, _| choose,
+                |chain| chain.step(test2, State::for_test2),
+                |chain| chain.step(|_| async { Ok::<_, DefinitionError>(Some('t')) }, |_, _| ()),
+            )
+            .step(move |(a, b)| test3(a, b || success), State::for_test3)
+            .step(test4, State::for_test4)
+    }
+
+    fn create_definition_with_child<P: StepPersister>(
+        definition_id: Uuid,
+        success: bool,
+        compensator: Arc<Compensator>,
+        p: P,
+    ) -> SagaDefinition<State, String, u32, DefinitionError, P> {
+        let lock_scope =
+            LockScope::from_id(definition_id, "create_definition_with_child".to_string());
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .compensate(curry!(Compensator::undo, compensator), |_, r| {
+                format!("test1 {r}")
+            })
+            .child(
+                move |scope, p| {
+                    SagaDefinition::<_, _, _, DefinitionError, _>::new(scope, State::new, 1, p)
+                        .step(test1, State::for_test1)
+                        .step(test2, State::for_test2)
+                        .step(move |(a, b)| test3(a, b || success), State::for_test3)
+                        .step(test4, State::for_test4)
+                },
+                |s, _| s.run_data.clone(),
+            )
+            .step(|r| async move { Ok::<_, DefinitionError>(r + 1) }, |_, r| r)
+    }
+
+    fn create_definition_with_waiting_child<P: StepPersister>(
+        lock_scope: LockScope,
+        p: P,
+    ) -> SagaDefinition<State, String, String, DefinitionError, P> {
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .child(
+                |scope, p| {
+                    SagaDefinition::<_, _, _, DefinitionError, _>::new(scope, State::new, 1, p)
+                        .step(test1, State::for_test1)
+                        .wait_for_signal::<String>("approval")
+                },
+                |s, _| s.run_data.clone(),
+            )
+    }
+
+    #[derive(Default)]
+    struct Processor {
+        failing: Option<usize>,
+        processed: RwLock<Vec<usize>>,
+    }
+
+    impl Processor {
+        async fn process(&self, item: usize) -> Result<usize, DefinitionError> {
+            self.processed.write().unwrap().push(item);
+            if self.failing == Some(item) {
+                return Err(DefinitionError(format!("item {item}")));
+            }
+            Ok(item * 2)
+        }
+    }
+
+    fn create_definition_with_for_each<P: StepPersister>(
+        definition_id: Uuid,
+        processor: Arc<Processor>,
+        concurrency: usize,
+        compensator: Option<Arc<Compensator>>,
+        p: P,
+    ) -> SagaDefinition<State, String, usize, DefinitionError, P> {
+        let lock_scope =
+            LockScope::from_id(definition_id, "create_definition_with_for_each".to_string());
+        let mut for_each = ForEach::new(move |item| {
+            let processor = processor.clone();
+            async move { processor.process(item).await }
+        })
+        .concurrency(concurrency);
+        if let Some(compensator) = compensator {
+            for_each = for_each.compensate(
+                move |item| {
+                    let compensator = compensator.clone();
+                    async move { compensator.undo(item).await }
+                },
+                |_, r: usize| format!("item {r}"),
+            );
+        }
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .for_each(for_each, |_, _| (0..5).collect())
+            .step(
+                |r: Vec<usize>| async move { Ok::<_, DefinitionError>(r.into_iter().sum()) },
+                |_, r| r,
+            )
+    }
+
+    fn create_definition_with_names<P: StepPersister>(
+        definition_id: Uuid,
+        inserted_step: bool,
+        success: bool,
+        p: P,
+    ) -> SagaDefinition<State, String, u32, DefinitionError, P> {
+        let lock_scope =
+            LockScope::from_id(definition_id, "create_definition_with_names".to_string());
+        let definition = SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .name("test1");
+        let definition = if inserted_step {
+            definition.step(|r| async move { Ok::<_, DefinitionError>(r) }, |_, r| r)
+        } else {
+            definition
+        };
+        definition
+            .step(test2, State::for_test2)
+            .name("test2")
+            .step(move |(a, b)| test3(a, b || success), State::for_test3)
+            .name("test3")
+            .step(test4, State::for_test4)
+    }
+
+    fn create_definition_with_heartbeat<P: StepPersister>(
+        lock_scope: LockScope,
+        compensator: Arc<Compensator>,
+        p: P,
+    ) -> SagaDefinition<State, String, bool, DefinitionError, P> {
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .compensate(curry!(Compensator::undo, compensator), |_, r| {
+                format!("test1 {r}")
+            })
+            .step(
+                |_| async {
+                    sleep(Duration::from_millis(100)).await;
+                    Ok::<_, DefinitionError>(true)
+                },
+                |_, _| (),
+            )
+            .heartbeat(Duration::from_millis(5))
+    }
+
+    fn create_definition_with_pause<P: StepPersister>(
+        lock_scope: LockScope,
+        p: P,
+    ) -> SagaDefinition<State, String, Option<char>, DefinitionError, P> {
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .step(
+                |_| async {
+                    sleep(Duration::from_millis(50)).await;
+                    Ok::<_, DefinitionError>(true)
+                },
+                |_, _| (),
+            )
+            .step(test2, |_, _| "paused".to_string())
+    }
+
+    fn create_definition_with_signal<P: StepPersister>(
+        lock_scope: LockScope,
+        p: P,
+    ) -> SagaDefinition<State, String, Option<char>, DefinitionError, P> {
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .wait_for_signal::<String>("approval")
+            .step(test2, |_, approval| approval)
+    }
+
+    fn create_definition_with_repeated_signal<P: StepPersister>(
+        lock_scope: LockScope,
+        p: P,
+    ) -> SagaDefinition<State, String, String, DefinitionError, P> {
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .wait_for_signal::<String>("approval")
+            .wait_for_signal::<String>("approval")
+    }
+
+    fn create_definition_with_sleep<P: StepPersister>(
+        lock_scope: LockScope,
+        p: P,
+    ) -> SagaDefinition<State, String, Option<char>, DefinitionError, P> {
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(test1, State::for_test1)
+            .sleep(Duration::from_millis(40))
+            .step(test2, |_, r| r.to_string())
+    }
+
+    fn create_definition_with_context<P: StepPersister>(
+        lock_scope: LockScope,
+        contexts: Arc<RwLock<Vec<StepContext>>>,
+        fail: bool,
+        p: P,
+    ) -> SagaDefinition<State, String, Option<char>, DefinitionError, P> {
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step_with_context(
+                move |v: usize, context: StepContext| async move {
+                    contexts.write().unwrap().push(context);
+                    if fail {
+                        return Err(DefinitionError("ticket".to_string()));
+                    }
+                    test1(v).await
+                },
+                State::for_test1,
+            )
+            .name("ticket")
+            .step(test2, State::for_test2)
+    }
+
+    fn create_definition_with_verify<P: StepPersister>(
+        lock_scope: LockScope,
+        executed: Arc<AtomicU32>,
+        verified: Option<bool>,
+        p: P,
+    ) -> SagaDefinition<State, String, Option<char>, DefinitionError, P> {
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .step(
+                move |v: usize| async move {
+                    executed.fetch_add(1, Ordering::SeqCst);
+                    test1(v).await
+                },
+                State::for_test1,
+            )
+            .verify(move |_| async move { Ok::<_, DefinitionError>(verified) })
+            .step(test2, State::for_test2)
+    }
+
+    // crash after the operation of the first step started, before its result was persisted
+    async fn store_started_step(persister: &InMemoryPersister, lock_scope: &LockScope) {
+        let token = persister
+            .lock(lock_scope.clone(), LockType::Initial)
+            .await
+            .unwrap();
+        persister
+            .store(
+                lock_scope.id,
+                token,
+                StepKey::Index(0),
+                "\"run data\"".to_string(),
+            )
+            .await
+            .unwrap();
+        persister
+            .store_started(lock_scope.id, token, StepKey::Index(1))
+            .await
+            .unwrap();
+        persister
+            .lock(lock_scope.clone(), LockType::Failed)
+            .await
+            .unwrap();
+    }
+
+    fn create_definition_with_transaction<P: TransactionalPersister>(
+        lock_scope: LockScope,
+        p: P,
+    ) -> SagaDefinition<State, String, (String, String), DefinitionError, P> {
+        SagaDefinition::new(lock_scope, State::new, 1, p)
+            .transactional_step(
+                |v: usize, transaction| async move {
+                    Ok::<_, DefinitionError>((test1(v).await?, transaction))
+                },
+                State::for_test1,
+            )
+            .step(test2, State::for_test2)
+            .step(|(a, b)| test3(a, b), State::for_test3)
+    }
+
+    fn create_definition_with_error(
+        success: bool,
+    ) -> SagaDefinition<State, String, Option<char>, DefinitionError, Blackhole> {
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "create_definition2".to_string());
+        SagaDefinition::new(lock_scope, State::new, 3, Blackhole::default())
+            .step(curry!(produce_error, success), State::for_test1)
+            .on_error(stop_on_error, State::handle_produce_error)
+            .step(test2, State::for_test2)
+    }
+
+    #[tokio::test]
+    async fn test_definition1() {
+        let definition = create_definition1();
+        let run_with_data = "run data".to_string();
+        let result = definition.run(run_with_data.clone()).await.unwrap();
+        assert_eq!(0, result);
+    }
+
+    #[tokio::test]
+    async fn test_definition2() {
+        let definition = create_definition2();
+        let run_with_data = "run data".to_string();
+        let result = definition.run(run_with_data.clone()).await.unwrap();
+        assert_eq!(Some('t'), result);
+    }
+
+    #[tokio::test]
+    async fn test_definition3() {
+        let definition_id = Uuid::new_v4();
+        let definition = create_definition3(definition_id, 1, false, Blackhole::default());
+        let run_with_data = "run data".to_string();
+        let result = definition.run(run_with_data.clone()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+
+        let definition = create_definition3(definition_id, 6, false, Blackhole::default());
+        let run_with_data = "run data".to_string();
+        let result = definition.run(run_with_data.clone()).await.unwrap();
+        assert_eq!(13, result);
+    }
+
+    #[tokio::test]
+    async fn test_definition3_continue() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
         let definition = create_definition3(definition_id, 1, false, persister.clone());
         let run_with_data = "run data".to_string();
         let result = definition.run(run_with_data.clone()).await;
@@ -574,4 +2611,1222 @@ mod tests {
         let result = definition.run(run_with_data.clone()).await;
         assert!(matches!(result, Err(DefinitionError(_))));
     }
+
+    #[tokio::test]
+    async fn test_definition_with_compensation() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let compensator = Arc::new(Compensator::default());
+        let definition = create_definition_with_compensation(
+            definition_id,
+            false,
+            compensator.clone(),
+            persister.clone(),
+        );
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+        assert_eq!(
+            vec!["test2 Some('f')".to_string(), "test1 false".to_string()],
+            *compensator.undone.read().unwrap()
+        );
+        assert!(matches!(
+            persister.retrieve(definition_id).await,
+            Err(PersistError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_compensation_success() {
+        let compensator = Arc::new(Compensator::default());
+        let definition = create_definition_with_compensation(
+            Uuid::new_v4(),
+            true,
+            compensator.clone(),
+            Blackhole::default(),
+        );
+        let result = definition.run("run data".to_string()).await.unwrap();
+        assert_eq!(13, result);
+        assert!(compensator.undone.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_compensation_continue() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let compensator = Arc::new(Compensator {
+            failing: Some("test1"),
+            ..Default::default()
+        });
+        let definition = create_definition_with_compensation(
+            definition_id,
+            false,
+            compensator.clone(),
+            persister.clone(),
+        );
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test1 false".to_string())), result);
+        assert_eq!(
+            vec!["test2 Some('f')".to_string()],
+            *compensator.undone.read().unwrap()
+        );
+        let saga = persister.retrieve(definition_id).await.unwrap();
+        assert!(saga.cancelled);
+        assert_eq!(1, saga.compensations.len());
+
+        let compensator = Arc::new(Compensator::default());
+        let definition = create_definition_with_compensation(
+            definition_id,
+            true,
+            compensator.clone(),
+            persister.clone(),
+        );
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(
+            Err(DefinitionError(PersistError::Cancelled.to_string())),
+            result
+        );
+        assert_eq!(
+            vec!["test1 false".to_string()],
+            *compensator.undone.read().unwrap()
+        );
+        assert!(matches!(
+            persister.retrieve(definition_id).await,
+            Err(PersistError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_retry() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let flaky = Arc::new(Flaky {
+            failures: 2,
+            ..Default::default()
+        });
+        let definition =
+            create_definition_with_retry(definition_id, false, flaky.clone(), persister.clone());
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+        assert_eq!(3, flaky.calls.load(Ordering::SeqCst));
+        let saga = persister.retrieve(definition_id).await.unwrap();
+        assert_eq!(Some(&3), saga.attempts.get(&StepKey::Index(1)));
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_retry_continue() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let flaky = Arc::new(Flaky {
+            failures: 5,
+            ..Default::default()
+        });
+        let definition =
+            create_definition_with_retry(definition_id, true, flaky.clone(), persister.clone());
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("flaky".to_string())), result);
+        assert_eq!(3, flaky.calls.load(Ordering::SeqCst));
+        let saga = persister.retrieve(definition_id).await.unwrap();
+        assert_eq!(Some(&3), saga.attempts.get(&StepKey::Index(1)));
+
+        let definition =
+            create_definition_with_retry(definition_id, true, flaky.clone(), persister.clone());
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(Err(DefinitionError("flaky".to_string())), result);
+        assert_eq!(4, flaky.calls.load(Ordering::SeqCst));
+
+        let flaky = Arc::new(Flaky::default());
+        let definition =
+            create_definition_with_retry(definition_id, true, flaky.clone(), persister.clone());
+        let result = definition.continue_from_last_step().await.unwrap();
+        assert_eq!(13, result);
+        assert_eq!(1, flaky.calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_timeout_fail() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let compensator = Arc::new(Compensator::default());
+        let definition = create_definition_with_timeout(
+            definition_id,
+            TimeoutOutcome::Fail,
+            compensator.clone(),
+            persister.clone(),
+        );
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(
+            Err(DefinitionError(PersistError::Timeout.to_string())),
+            result
+        );
+        assert!(compensator.undone.read().unwrap().is_empty());
+        let saga = persister.retrieve(definition_id).await.unwrap();
+        assert!(!saga.cancelled);
+        assert!(saga.states.contains_key(&StepKey::Index(1)));
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_timeout_compensate() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let compensator = Arc::new(Compensator::default());
+        let definition = create_definition_with_timeout(
+            definition_id,
+            TimeoutOutcome::Compensate,
+            compensator.clone(),
+            persister.clone(),
+        );
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(
+            Err(DefinitionError(PersistError::Timeout.to_string())),
+            result
+        );
+        assert_eq!(
+            vec!["test1 false".to_string()],
+            *compensator.undone.read().unwrap()
+        );
+        assert!(matches!(
+            persister.retrieve(definition_id).await,
+            Err(PersistError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_definition_deadline_is_persisted() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let definition = create_definition3(definition_id, 1, false, persister.clone())
+            .deadline(Duration::from_millis(20), TimeoutOutcome::Fail);
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+        let deadline = persister.retrieve(definition_id).await.unwrap().deadline;
+        assert!(deadline.is_some());
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let definition = create_definition3(definition_id, 6, true, persister.clone())
+            .deadline(Duration::from_millis(20), TimeoutOutcome::Fail);
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(
+            Err(DefinitionError(PersistError::Timeout.to_string())),
+            result
+        );
+        let saga = persister.retrieve(definition_id).await.unwrap();
+        assert_eq!(deadline, saga.deadline);
+    }
+
+    #[tokio::test]
+    async fn test_definition_past_deadline_stops() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let definition = create_definition3(definition_id, 1, false, persister.clone())
+            .deadline(Duration::from_millis(20), TimeoutOutcome::Fail);
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let next = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert_eq!(Some(definition_id), next.map(|(id, _, _)| id));
+        // let the retry lock taken above expire
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let definition = create_definition3(definition_id, 6, true, persister.clone())
+            .deadline(Duration::from_millis(20), TimeoutOutcome::Fail);
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(
+            Err(DefinitionError(PersistError::Timeout.to_string())),
+            result
+        );
+        // retrying cannot succeed anymore
+        assert!(persister
+            .get_next_failed(Duration::ZERO)
+            .await
+            .unwrap()
+            .is_none());
+        let definition = create_definition3(definition_id, 6, true, persister.clone())
+            .deadline(Duration::from_millis(20), TimeoutOutcome::Fail);
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(
+            Err(DefinitionError(PersistError::Cancelled.to_string())),
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_parallel_continue() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let counter = Arc::new(Flaky::default());
+        let flaky = Arc::new(Flaky {
+            failures: 1,
+            ..Default::default()
+        });
+        let compensator = Arc::new(Compensator {
+            failing: Some("counter"),
+            ..Default::default()
+        });
+        let definition = create_definition_with_parallel(
+            definition_id,
+            flaky.clone(),
+            counter.clone(),
+            compensator.clone(),
+            persister.clone(),
+        );
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("counter false".to_string())), result);
+        assert_eq!(1, counter.calls.load(Ordering::SeqCst));
+        assert_eq!(1, flaky.calls.load(Ordering::SeqCst));
+
+        let compensator = Arc::new(Compensator::default());
+        let definition = create_definition_with_parallel(
+            definition_id,
+            flaky.clone(),
+            counter.clone(),
+            compensator.clone(),
+            persister.clone(),
+        );
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(
+            Err(DefinitionError(PersistError::Cancelled.to_string())),
+            result
+        );
+        assert_eq!(1, counter.calls.load(Ordering::SeqCst));
+        assert_eq!(1, flaky.calls.load(Ordering::SeqCst));
+        assert_eq!(
+            vec!["counter false".to_string()],
+            *compensator.undone.read().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_parallel_resumes_unfinished_branches() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let counter = Arc::new(Flaky::default());
+        let flaky = Arc::new(Flaky {
+            failures: 1,
+            ..Default::default()
+        });
+        let compensator = Arc::new(Compensator::default());
+        let lock_scope = LockScope::from_id(definition_id, "parallel".to_string());
+        let definition = SagaDefinition::<_, _, _, DefinitionError, _>::new(
+            lock_scope,
+            State::new,
+            1,
+            persister.clone(),
+        )
+        .parallel(
+            ParallelBranch::new(curry!(Flaky::call, counter.clone())),
+            ParallelBranch::new(curry!(Flaky::call, flaky.clone())),
+            |s, initial| (s.for_test1(initial), 11),
+        );
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("flaky".to_string())), result);
+
+        let lock_scope = LockScope::from_id(definition_id, "parallel".to_string());
+        let definition = SagaDefinition::<_, _, _, DefinitionError, _>::new(
+            lock_scope,
+            State::new,
+            1,
+            persister.clone(),
+        )
+        .parallel(
+            ParallelBranch::new(curry!(Flaky::call, counter.clone())),
+            ParallelBranch::new(curry!(Flaky::call, flaky.clone())),
+            |s, initial| (s.for_test1(initial), 11),
+        )
+        .step(
+            curry!(Compensator::undo, compensator.clone()),
+            |_, (a, b)| format!("{a} {b}"),
+        );
+        definition.continue_from_last_step().await.unwrap();
+        assert_eq!(1, counter.calls.load(Ordering::SeqCst));
+        assert_eq!(2, flaky.calls.load(Ordering::SeqCst));
+        assert_eq!(
+            vec!["false true".to_string()],
+            *compensator.undone.read().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_branch() {
+        let definition = create_definition_with_branch(Uuid::new_v4(), true, false, Blackhole {});
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+
+        let definition = create_definition_with_branch(Uuid::new_v4(), false, false, Blackhole {});
+        let result = definition.run("run data".to_string()).await.unwrap();
+        assert_eq!(13, result);
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_branch_continue() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let definition =
+            create_definition_with_branch(definition_id, true, false, persister.clone());
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+
+        // predicate would choose the other chain now
+        let definition =
+            create_definition_with_branch(definition_id, false, false, persister.clone());
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+        let saga = persister.retrieve(definition_id).await.unwrap();
+        assert_eq!(
+            Some(&"true".to_string()),
+            saga.states.get(&StepKey::Index(2))
+        );
+
+        let definition = create_definition_with_branch(definition_id, false, true, persister);
+        let result = definition.continue_from_last_step().await.unwrap();
+        assert_eq!(13, result);
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_child() {
+        let compensator = Arc::new(Compensator::default());
+        let definition =
+            create_definition_with_child(Uuid::new_v4(), true, compensator, Blackhole {});
+        let result = definition.run("run data".to_string()).await.unwrap();
+        assert_eq!(14, result);
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_child_continue() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let compensator = Arc::new(Compensator::default());
+        let definition = create_definition_with_child(
+            definition_id,
+            false,
+            compensator.clone(),
+            persister.clone(),
+        );
+        let child_scope = definition.lock_scope().child(&StepKey::Index(2));
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+        // unfinished child stops the parent without compensation
+        assert!(compensator.undone.read().unwrap().is_empty());
+        let child = persister.retrieve(child_scope.id).await.unwrap();
+        assert_eq!(
+            Some(&"false".to_string()),
+            child.states.get(&StepKey::Index(1))
+        );
+
+        let next = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert_eq!(Some(definition_id), next.map(|(id, _, _)| id));
+        // let the retry lock taken above expire
+        sleep(Duration::from_millis(10)).await;
+
+        let definition = create_definition_with_child(
+            definition_id,
+            true,
+            compensator.clone(),
+            persister.clone(),
+        );
+        let result = definition.continue_from_last_step().await.unwrap();
+        assert_eq!(14, result);
+        assert!(compensator.undone.read().unwrap().is_empty());
+        assert!(matches!(
+            persister.retrieve(child_scope.id).await,
+            Err(PersistError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_cancelled_child() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let compensator = Arc::new(Compensator::default());
+        let lock_scope = LockScope::from_id(definition_id, "cancelled_child".to_string());
+        let definition = SagaDefinition::new(lock_scope, State::new, 1, persister.clone())
+            .step(test1, State::for_test1)
+            .compensate(curry!(Compensator::undo, compensator.clone()), |_, r| {
+                format!("test1 {r}")
+            })
+            .child(
+                |scope, p| {
+                    SagaDefinition::<_, _, _, DefinitionError, _>::new(scope, State::new, 3, p)
+                        .step(curry!(produce_error, false), State::for_test1)
+                        .on_error(stop_on_error, State::handle_produce_error)
+                },
+                |s, _| s.run_data.clone(),
+            );
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("produce_error".to_string())), result);
+        assert_eq!(
+            vec!["test1 false".to_string()],
+            *compensator.undone.read().unwrap()
+        );
+        assert!(matches!(
+            persister.retrieve(definition_id).await,
+            Err(PersistError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_waiting_child() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "waiting_child".to_string());
+        let child_scope = lock_scope.child(&StepKey::Index(2));
+        let definition =
+            create_definition_with_waiting_child(lock_scope.clone(), persister.clone());
+        assert_eq!(
+            Err(DefinitionError(PersistError::Waiting.to_string())),
+            definition.run("run data".to_string()).await
+        );
+        // parent waits with the child instead of failing
+        sleep(Duration::from_millis(10)).await;
+        assert!(persister
+            .get_next_failed(Duration::ZERO)
+            .await
+            .unwrap()
+            .is_none());
+
+        persister
+            .signal(child_scope.id, "approval", "\"approved\"".to_string())
+            .await
+            .unwrap();
+        let (id, name, executor_id) = persister
+            .get_next_failed(Duration::ZERO)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(lock_scope.id, id);
+        let definition = create_definition_with_waiting_child(
+            LockScope {
+                executor_id,
+                ..LockScope::from_id(id, name)
+            },
+            persister.clone(),
+        );
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(Ok("approved".to_string()), result);
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_sleeping_child() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "sleeping_child".to_string());
+        let create_definition = |lock_scope| {
+            SagaDefinition::new(lock_scope, State::new, 1, persister.clone())
+                .step(test1, State::for_test1)
+                .child(
+                    |scope, p| {
+                        SagaDefinition::<_, _, _, DefinitionError, _>::new(scope, State::new, 1, p)
+                            .step(test1, State::for_test1)
+                            .sleep(Duration::from_millis(50))
+                    },
+                    |s, _| s.run_data.clone(),
+                )
+        };
+        assert_eq!(
+            Err(DefinitionError(PersistError::Sleeping.to_string())),
+            create_definition(lock_scope.clone())
+                .run("run data".to_string())
+                .await
+        );
+        // parent sleeps with the child instead of failing
+        sleep(Duration::from_millis(10)).await;
+        assert!(persister
+            .get_next_failed(Duration::ZERO)
+            .await
+            .unwrap()
+            .is_none());
+
+        sleep(Duration::from_millis(50)).await;
+        let (id, name, executor_id) = persister
+            .get_next_failed(Duration::ZERO)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(lock_scope.id, id);
+        let result = create_definition(LockScope {
+            executor_id,
+            ..LockScope::from_id(id, name)
+        })
+        .continue_from_last_step()
+        .await;
+        assert_eq!(Ok(false), result);
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_paused_child() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "paused_child".to_string());
+        let child_scope = lock_scope.child(&StepKey::Index(2));
+        persister
+            .pause(SagaSelector::Id(child_scope.id))
+            .await
+            .unwrap();
+        persister
+            .signal(child_scope.id, "approval", "\"approved\"".to_string())
+            .await
+            .unwrap();
+        let definition =
+            create_definition_with_waiting_child(lock_scope.clone(), persister.clone());
+        assert_eq!(
+            Err(DefinitionError(PersistError::Paused.to_string())),
+            definition.run("run data".to_string()).await
+        );
+        assert!(persister
+            .get_next_failed(Duration::ZERO)
+            .await
+            .unwrap()
+            .is_none());
+
+        persister
+            .unpause(SagaSelector::Id(child_scope.id))
+            .await
+            .unwrap();
+        let (id, name, executor_id) = persister
+            .get_next_failed(Duration::ZERO)
+            .await
+            .unwrap()
+            .unwrap();
+        let definition = create_definition_with_waiting_child(
+            LockScope {
+                executor_id,
+                ..LockScope::from_id(id, name)
+            },
+            persister.clone(),
+        );
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(Ok("approved".to_string()), result);
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_for_each() {
+        let processor = Arc::new(Processor::default());
+        let definition = create_definition_with_for_each(
+            Uuid::new_v4(),
+            processor.clone(),
+            3,
+            None,
+            Blackhole {},
+        );
+        let result = definition.run("run data".to_string()).await.unwrap();
+        assert_eq!(20, result);
+        let mut processed = processor.processed.read().unwrap().clone();
+        processed.sort();
+        assert_eq!(vec![0, 1, 2, 3, 4], processed);
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_for_each_continue() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let processor = Arc::new(Processor {
+            failing: Some(3),
+            ..Default::default()
+        });
+        let definition = create_definition_with_for_each(
+            definition_id,
+            processor.clone(),
+            1,
+            None,
+            persister.clone(),
+        );
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("item 3".to_string())), result);
+        assert_eq!(vec![0, 1, 2, 3], *processor.processed.read().unwrap());
+
+        let processor = Arc::new(Processor::default());
+        let definition =
+            create_definition_with_for_each(definition_id, processor.clone(), 1, None, persister);
+        let result = definition.continue_from_last_step().await.unwrap();
+        assert_eq!(20, result);
+        assert_eq!(vec![3, 4], *processor.processed.read().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_for_each_compensation() {
+        let processor = Arc::new(Processor {
+            failing: Some(3),
+            ..Default::default()
+        });
+        let compensator = Arc::new(Compensator::default());
+        let definition = create_definition_with_for_each(
+            Uuid::new_v4(),
+            processor,
+            1,
+            Some(compensator.clone()),
+            InMemoryPersister::new(Duration::from_millis(5)),
+        );
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("item 3".to_string())), result);
+        assert_eq!(
+            vec!["item 4", "item 2", "item 0"],
+            *compensator.undone.read().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_for_each_compensation_continue() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let processor = Arc::new(Processor {
+            failing: Some(3),
+            ..Default::default()
+        });
+        let compensator = Arc::new(Compensator {
+            failing: Some("item 2"),
+            ..Default::default()
+        });
+        let definition = create_definition_with_for_each(
+            definition_id,
+            processor,
+            1,
+            Some(compensator.clone()),
+            persister.clone(),
+        );
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("item 2".to_string())), result);
+        assert_eq!(vec!["item 4"], *compensator.undone.read().unwrap());
+
+        let compensator = Arc::new(Compensator::default());
+        let definition = create_definition_with_for_each(
+            definition_id,
+            Arc::new(Processor::default()),
+            1,
+            Some(compensator.clone()),
+            persister.clone(),
+        );
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(
+            Err(DefinitionError(PersistError::Cancelled.to_string())),
+            result
+        );
+        assert_eq!(
+            vec!["item 2", "item 0"],
+            *compensator.undone.read().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_definition_with_names_survives_inserted_step() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let definition =
+            create_definition_with_names(definition_id, false, false, persister.clone());
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+        let saga = persister.retrieve(definition_id).await.unwrap();
+        assert_eq!(
+            Some(&"\"f\"".to_string()),
+            saga.states.get(&StepKey::Name("test2".to_string()))
+        );
+
+        let definition = create_definition_with_names(definition_id, true, true, persister);
+        let result = definition.continue_from_last_step().await.unwrap();
+        assert_eq!(13, result);
+    }
+
+    #[test]
+    #[should_panic(expected = "step name test1 is used already")]
+    fn test_duplicate_step_name() {
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "duplicate".to_string());
+        let _ = SagaDefinition::<_, _, _, DefinitionError, _>::new(
+            lock_scope,
+            State::new,
+            1,
+            Blackhole {},
+        )
+        .step(test1, State::for_test1)
+        .name("test1")
+        .step(test2, State::for_test2)
+        .name("test1");
+    }
+
+    #[tokio::test]
+    async fn test_definition_version_migration() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let definition =
+            create_definition_with_names(definition_id, false, false, persister.clone());
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+        let saga = persister.retrieve(definition_id).await.unwrap();
+        assert_eq!(Some(0), saga.version);
+
+        let definition = create_definition_with_names(definition_id, false, false, persister)
+            .version(1)
+            .migration(0, |states| {
+                states.insert(StepKey::Name("test2".to_string()), "\"t\"".to_string());
+                Ok(())
+            });
+        let result = definition.continue_from_last_step().await.unwrap();
+        assert_eq!(13, result);
+    }
+
+    #[tokio::test]
+    async fn test_definition_version_incompatible() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let definition =
+            create_definition_with_names(definition_id, false, false, persister.clone()).version(2);
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+
+        let definition =
+            create_definition_with_names(definition_id, false, true, persister.clone()).version(1);
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(
+            Err(DefinitionError(
+                "Saga version 2 is incompatible with definition version 1".to_string()
+            )),
+            result
+        );
+        let saga = persister.retrieve(definition_id).await.unwrap();
+        assert_eq!(Some(2), saga.version);
+        let next = persister
+            .get_next_failed(Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(Some(definition_id), next.map(|(id, _, _)| id));
+    }
+
+    #[tokio::test]
+    async fn test_definition_heartbeat_keeps_lock() {
+        let persister = InMemoryPersister::new(Duration::from_millis(20));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "heartbeat".to_string());
+        let definition = create_definition_with_heartbeat(
+            lock_scope.clone(),
+            Arc::new(Compensator::default()),
+            persister.clone(),
+        );
+        let running = tokio::spawn(definition.run("run data".to_string()));
+
+        sleep(Duration::from_millis(60)).await;
+        let other = LockScope::from_id(lock_scope.id, lock_scope.name);
+        let result = persister.lock(other, LockType::Executing).await;
+        assert!(matches!(result, Err(PersistError::Locked)), "{result:?}");
+        assert_eq!(Ok(true), running.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_definition_stops_when_lock_lost() {
+        let persister = InMemoryPersister::new(Duration::from_secs(1));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "heartbeat".to_string());
+        let compensator = Arc::new(Compensator::default());
+        let definition = create_definition_with_heartbeat(
+            lock_scope.clone(),
+            compensator.clone(),
+            persister.clone(),
+        );
+        let running = tokio::spawn(definition.run("run data".to_string()));
+
+        sleep(Duration::from_millis(20)).await;
+        // another executor takes over the saga
+        persister
+            .lock(lock_scope.clone(), LockType::Failed)
+            .await
+            .unwrap();
+        let other = LockScope::from_id(lock_scope.id, lock_scope.name.clone());
+        persister.lock(other, LockType::Executing).await.unwrap();
+
+        assert_eq!(
+            Err(DefinitionError(
+                "Lock was taken by another executor".to_string()
+            )),
+            running.await.unwrap()
+        );
+        assert!(compensator.undone.read().unwrap().is_empty());
+        let third = LockScope::from_id(lock_scope.id, lock_scope.name);
+        let result = persister.lock(third, LockType::Executing).await;
+        assert!(matches!(result, Err(PersistError::Locked)), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_definition_rejects_writes_after_lock_expired() {
+        let persister = InMemoryPersister::new(Duration::from_millis(10));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "fenced".to_string());
+        let compensator = Arc::new(Compensator::default());
+        let definition = SagaDefinition::new(lock_scope.clone(), State::new, 1, persister.clone())
+            .step(test1, State::for_test1)
+            .compensate(curry!(Compensator::undo, compensator.clone()), |_, r| {
+                format!("test1 {r}")
+            })
+            .step(
+                |_| async {
+                    sleep(Duration::from_millis(60)).await;
+                    Ok::<_, DefinitionError>(true)
+                },
+                |_, _| (),
+            );
+        let running = tokio::spawn(definition.run("run data".to_string()));
+
+        sleep(Duration::from_millis(30)).await;
+        // lock expired without heartbeat, another executor takes over
+        let other = LockScope::from_id(lock_scope.id, lock_scope.name.clone());
+        persister.lock(other, LockType::Executing).await.unwrap();
+
+        assert_eq!(
+            Err(DefinitionError(
+                "Fencing token is stale, saga is locked by a newer lock".to_string()
+            )),
+            running.await.unwrap()
+        );
+        assert!(compensator.undone.read().unwrap().is_empty());
+        let saga = persister.retrieve(lock_scope.id).await.unwrap();
+        assert!(!saga.states.contains_key(&StepKey::Index(2)));
+        assert!(!saga.cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_definition_cancelled_while_running() {
+        let persister = InMemoryPersister::new(Duration::from_secs(1));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "cancelled".to_string());
+        let compensator = Arc::new(Compensator::default());
+        let definition = SagaDefinition::new(lock_scope.clone(), State::new, 1, persister.clone())
+            .step(test1, State::for_test1)
+            .compensate(curry!(Compensator::undo, compensator.clone()), |_, r| {
+                format!("test1 {r}")
+            })
+            .step(
+                |_| async {
+                    sleep(Duration::from_millis(50)).await;
+                    Ok::<_, DefinitionError>(true)
+                },
+                |_, _| (),
+            )
+            .step(test1, |_, _| 11);
+        let running = tokio::spawn(definition.run("run data".to_string()));
+
+        sleep(Duration::from_millis(20)).await;
+        persister.cancel(lock_scope.id).await.unwrap();
+
+        assert_eq!(
+            Err(DefinitionError(PersistError::Cancelled.to_string())),
+            running.await.unwrap()
+        );
+        assert_eq!(
+            vec!["test1 false".to_string()],
+            *compensator.undone.read().unwrap()
+        );
+        // cancelled saga is kept and never resumed
+        let saga = persister.retrieve(lock_scope.id).await.unwrap();
+        assert!(saga.cancel_requested);
+        assert!(!saga.states.contains_key(&StepKey::Index(3)));
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert!(result.is_none(), "{result:?}");
+        let other = LockScope::from_id(lock_scope.id, lock_scope.name);
+        let result = persister.lock(other, LockType::Executing).await;
+        assert!(matches!(result, Err(PersistError::Cancelled)), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_definition_cancelled_after_failure() {
+        let definition_id = Uuid::new_v4();
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(definition_id, "cancelled".to_string());
+        let token = persister
+            .lock(lock_scope.clone(), LockType::Executing)
+            .await
+            .unwrap();
+        persister
+            .store(
+                definition_id,
+                token,
+                StepKey::Index(0),
+                "\"run data\"".to_string(),
+            )
+            .await
+            .unwrap();
+        persister
+            .store(definition_id, token, StepKey::Index(1), "false".to_string())
+            .await
+            .unwrap();
+        persister
+            .lock(lock_scope.clone(), LockType::Failed)
+            .await
+            .unwrap();
+        persister.cancel(definition_id).await.unwrap();
+
+        let compensator = Arc::new(Compensator::default());
+        let definition = create_definition_with_compensation(
+            definition_id,
+            true,
+            compensator.clone(),
+            persister.clone(),
+        );
+        let result = definition.continue_from_last_step().await;
+        assert_eq!(
+            Err(DefinitionError(PersistError::Cancelled.to_string())),
+            result
+        );
+        assert_eq!(
+            vec!["test1 false".to_string()],
+            *compensator.undone.read().unwrap()
+        );
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert!(result.is_none(), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_definition_paused_and_resumed() {
+        let persister = InMemoryPersister::new(Duration::from_secs(1));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "paused".to_string());
+        let definition = create_definition_with_pause(lock_scope.clone(), persister.clone());
+        let running = tokio::spawn(definition.run("run data".to_string()));
+
+        sleep(Duration::from_millis(20)).await;
+        persister
+            .pause(SagaSelector::Id(lock_scope.id))
+            .await
+            .unwrap();
+        assert_eq!(
+            Err(DefinitionError(PersistError::Paused.to_string())),
+            running.await.unwrap()
+        );
+        let saga = persister.retrieve(lock_scope.id).await.unwrap();
+        assert!(!saga.cancelled);
+        assert!(!saga.states.contains_key(&StepKey::Index(3)));
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert!(result.is_none(), "{result:?}");
+
+        persister
+            .unpause(SagaSelector::Id(lock_scope.id))
+            .await
+            .unwrap();
+        let (id, name, executor_id) = persister
+            .get_next_failed(Duration::ZERO)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(lock_scope.id, id);
+        let definition = create_definition_with_pause(
+            LockScope {
+                executor_id,
+                ..LockScope::from_id(id, name)
+            },
+            persister.clone(),
+        );
+        assert_eq!(Ok(Some('p')), definition.continue_from_last_step().await);
+    }
+
+    #[tokio::test]
+    async fn test_definition_waits_for_signal() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "signal".to_string());
+        let definition = create_definition_with_signal(lock_scope.clone(), persister.clone());
+        assert_eq!(
+            Err(DefinitionError(PersistError::Waiting.to_string())),
+            definition.run("run data".to_string()).await
+        );
+        sleep(Duration::from_millis(10)).await;
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert!(result.is_none(), "{result:?}");
+
+        persister
+            .signal(lock_scope.id, "approval", "\"yes\"".to_string())
+            .await
+            .unwrap();
+        let (id, name, executor_id) = persister
+            .get_next_failed(Duration::ZERO)
+            .await
+            .unwrap()
+            .unwrap();
+        let definition = create_definition_with_signal(
+            LockScope {
+                executor_id,
+                ..LockScope::from_id(id, name)
+            },
+            persister.clone(),
+        );
+        assert_eq!(Ok(Some('y')), definition.continue_from_last_step().await);
+    }
+
+    #[tokio::test]
+    async fn test_definition_consumes_signal() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "signal".to_string());
+        let definition =
+            create_definition_with_repeated_signal(lock_scope.clone(), persister.clone());
+        assert_eq!(
+            Err(DefinitionError(PersistError::Waiting.to_string())),
+            definition.run("run data".to_string()).await
+        );
+
+        for payload in ["first", "second"] {
+            persister
+                .signal(lock_scope.id, "approval", format!("\"{payload}\""))
+                .await
+                .unwrap();
+            let (id, name, executor_id) = persister
+                .get_next_failed(Duration::ZERO)
+                .await
+                .unwrap()
+                .unwrap();
+            let definition = create_definition_with_repeated_signal(
+                LockScope {
+                    executor_id,
+                    ..LockScope::from_id(id, name)
+                },
+                persister.clone(),
+            );
+            let result = definition.continue_from_last_step().await;
+            if payload == "first" {
+                // second wait for the name does not get the consumed payload
+                assert_eq!(
+                    Err(DefinitionError(PersistError::Waiting.to_string())),
+                    result
+                );
+                let saga = persister.retrieve(lock_scope.id).await.unwrap();
+                assert!(saga.signals.is_empty());
+            } else {
+                assert_eq!(Ok("second".to_string()), result);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_definition_sleeps_durably() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "sleep".to_string());
+        let definition = create_definition_with_sleep(lock_scope.clone(), persister.clone());
+        assert_eq!(
+            Err(DefinitionError(PersistError::Sleeping.to_string())),
+            definition.run("run data".to_string()).await
+        );
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert!(result.is_none(), "{result:?}");
+
+        sleep(Duration::from_millis(20)).await;
+        // wake up time is kept, resuming does not restart the timer
+        let definition = create_definition_with_sleep(lock_scope.clone(), persister.clone());
+        assert_eq!(
+            Err(DefinitionError(PersistError::Sleeping.to_string())),
+            definition.continue_from_last_step().await
+        );
+
+        sleep(Duration::from_millis(25)).await;
+        let (id, name, executor_id) = persister
+            .get_next_failed(Duration::ZERO)
+            .await
+            .unwrap()
+            .unwrap();
+        let definition = create_definition_with_sleep(
+            LockScope {
+                executor_id,
+                ..LockScope::from_id(id, name)
+            },
+            persister.clone(),
+        );
+        assert_eq!(Ok(Some('f')), definition.continue_from_last_step().await);
+    }
+
+    #[tokio::test]
+    async fn test_step_context_is_stable_across_executions() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "context".to_string());
+        let contexts = Arc::new(RwLock::new(Vec::new()));
+        let definition = create_definition_with_context(
+            lock_scope.clone(),
+            contexts.clone(),
+            true,
+            persister.clone(),
+        );
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("ticket".to_string())), result);
+
+        let definition =
+            create_definition_with_context(lock_scope.clone(), contexts.clone(), false, persister);
+        let result = definition.continue_from_last_step().await.unwrap();
+        assert_eq!(Some('f'), result);
+
+        let expected = StepContext::new(lock_scope.id, StepKey::Name("ticket".to_string()));
+        assert_eq!(vec![expected.clone(), expected], *contexts.read().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_failed_step_is_marked_started() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "started".to_string());
+        let definition: SagaDefinition<_, _, _, DefinitionError, _> =
+            SagaDefinition::new(lock_scope.clone(), State::new, 1, persister.clone())
+                .step(
+                    |_: usize| async { Err::<bool, _>(DefinitionError("ticket".to_string())) },
+                    State::for_test1,
+                )
+                .name("ticket")
+                .verify(|_| async { Ok::<_, DefinitionError>(None) })
+                .step(test2, State::for_test2);
+        assert!(definition.run("run data".to_string()).await.is_err());
+
+        let saga = persister.retrieve(lock_scope.id).await.unwrap();
+        assert!(saga.started.contains(&StepKey::Name("ticket".to_string())));
+        assert!(!saga
+            .states
+            .contains_key(&StepKey::Name("ticket".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_unverified_step_is_not_marked_started() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "started".to_string());
+        let contexts = Arc::new(RwLock::new(Vec::new()));
+        let definition =
+            create_definition_with_context(lock_scope.clone(), contexts, true, persister.clone());
+        assert!(definition.run("run data".to_string()).await.is_err());
+
+        let saga = persister.retrieve(lock_scope.id).await.unwrap();
+        assert!(saga.started.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_verified_started_step_is_not_executed_again() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "verify".to_string());
+        store_started_step(&persister, &lock_scope).await;
+
+        let executed = Arc::new(AtomicU32::new(0));
+        let definition = create_definition_with_verify(
+            LockScope::from_id(lock_scope.id, "verify".to_string()),
+            executed.clone(),
+            Some(true),
+            persister,
+        );
+        let result = definition.continue_from_last_step().await.unwrap();
+        assert_eq!(Some('t'), result);
+        assert_eq!(0, executed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_unverified_started_step_is_executed_again() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "verify".to_string());
+        store_started_step(&persister, &lock_scope).await;
+
+        let executed = Arc::new(AtomicU32::new(0));
+        let definition = create_definition_with_verify(
+            LockScope::from_id(lock_scope.id, "verify".to_string()),
+            executed.clone(),
+            None,
+            persister,
+        );
+        let result = definition.continue_from_last_step().await.unwrap();
+        assert_eq!(Some('f'), result);
+        assert_eq!(1, executed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_transactional_step_commits_its_result() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let lock_scope = LockScope::from_id(Uuid::new_v4(), "transaction".to_string());
+        let definition = create_definition_with_transaction(lock_scope.clone(), persister.clone());
+        let result = definition.run("run data".to_string()).await;
+        assert_eq!(Err(DefinitionError("test3".to_string())), result);
+
+        let saga = persister.retrieve(lock_scope.id).await.unwrap();
+        assert_eq!(
+            Some(&"false".to_string()),
+            saga.states.get(&StepKey::Index(1))
+        );
+        assert_eq!(
+            Some(&"\"f\"".to_string()),
+            saga.states.get(&StepKey::Index(2))
+        );
+    }
 }
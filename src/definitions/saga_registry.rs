@@ -0,0 +1,210 @@
+use std::{collections::HashMap, error::Error, fmt, future::Future, pin::Pin};
+
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use crate::persisters::persister::{LockScope, PersistError, StepPersister};
+
+use super::saga_definition::{SagaDefinition, SagaRunner};
+
+pub type ResumeDefinition<E> =
+    Box<dyn Fn(LockScope) -> Pin<Box<dyn Future<Output = Result<(), E>> + Send>> + Send + Sync>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RegistryError<E> {
+    /// No definition is registered under the name of the saga
+    UnknownDefinition(String),
+    /// Resumed saga failed again
+    Saga(E),
+}
+
+impl<E: fmt::Display> fmt::Display for RegistryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownDefinition(name) => write!(f, "Unknown definition {name}"),
+            Self::Saga(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for RegistryError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::UnknownDefinition(_) => None,
+            Self::Saga(e) => Some(e),
+        }
+    }
+}
+
+/// Definitions registered under the name of their lock scope, used to resume failed sagas
+pub struct SagaRegistry<WrappingError> {
+    definitions: HashMap<String, ResumeDefinition<WrappingError>>,
+}
+
+impl<WrappingError> Default for SagaRegistry<WrappingError> {
+    fn default() -> Self {
+        Self {
+            definitions: HashMap::new(),
+        }
+    }
+}
+
+impl<WrappingError> SagaRegistry<WrappingError>
+where
+    WrappingError: From<PersistError> + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Factory rebuilds the definition for the lock scope of a saga being resumed
+    pub fn register<State, In, Out, Persister, Factory>(
+        mut self,
+        name: impl Into<String>,
+        factory: Factory,
+    ) -> Self
+    where
+        Factory: Fn(LockScope) -> SagaDefinition<State, In, Out, WrappingError, Persister>
+            + Send
+            + Sync
+            + 'static,
+        State: Send + Sync + 'static,
+        In: DeserializeOwned + Send + 'static,
+        Out: Send + 'static,
+        Persister: StepPersister,
+    {
+        self.definitions.insert(
+            name.into(),
+            Box::new(move |scope| {
+                let definition = factory(scope);
+                let parked = definition.parked();
+                Box::pin(async move {
+                    match definition.continue_from_last_step().await {
+                        Ok(_) => Ok(()),
+                        // parking is not a failure, the saga continues once woken up
+                        Err(_) if parked() => Ok(()),
+                        Err(e) => Err(e),
+                    }
+                })
+            }),
+        );
+        self
+    }
+
+    /// Continues the saga with the definition registered under the name,
+    /// arguments are the ones returned by `StepPersister::get_next_failed`.
+    /// A saga parking again, waiting, sleeping or paused, is resumed successfully
+    pub async fn resume(
+        &self,
+        id: Uuid,
+        name: &str,
+        executor_id: Uuid,
+    ) -> Result<(), RegistryError<WrappingError>> {
+        let definition = self
+            .definitions
+            .get(name)
+            .ok_or_else(|| RegistryError::UnknownDefinition(name.to_string()))?;
+        definition(LockScope {
+            id,
+            executor_id,
+            name: name.to_string(),
+            parent: None,
+            token: None,
+        })
+        .await
+        .map_err(RegistryError::Saga)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt::Display,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use crate::{
+        definitions::step_key::StepKey,
+        persisters::{in_memory::InMemoryPersister, persister::LockType},
+    };
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct ResumeError(String);
+
+    impl Display for ResumeError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl std::error::Error for ResumeError {}
+
+    impl From<PersistError> for ResumeError {
+        fn from(value: PersistError) -> Self {
+            Self(value.to_string())
+        }
+    }
+
+    fn create_registry(
+        persister: InMemoryPersister,
+        executed: Arc<AtomicU32>,
+    ) -> SagaRegistry<ResumeError> {
+        SagaRegistry::new().register("add", move |scope| {
+            let executed = executed.clone();
+            SagaDefinition::new(scope, |_: u32, _: &()| (), (), persister.clone()).step(
+                move |v: u32| async move {
+                    executed.fetch_add(v, Ordering::SeqCst);
+                    Ok::<_, ResumeError>(v)
+                },
+                |_, _| 2,
+            )
+        })
+    }
+
+    #[tokio::test]
+    async fn test_resume_registered_definition() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let executed = Arc::new(AtomicU32::new(0));
+        let registry = create_registry(persister.clone(), executed.clone());
+        let scope = LockScope::from_id(Uuid::new_v4(), "add".to_string());
+        let token = persister
+            .lock(scope.clone(), LockType::Initial)
+            .await
+            .unwrap();
+        persister
+            .store(scope.id, token, StepKey::Index(0), "1".to_string())
+            .await
+            .unwrap();
+        persister
+            .lock(scope.clone(), LockType::Failed)
+            .await
+            .unwrap();
+
+        let (id, name, executor_id) = persister
+            .get_next_failed(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .unwrap();
+        registry.resume(id, &name, executor_id).await.unwrap();
+        assert_eq!(2, executed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_resume_unknown_definition() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let registry = create_registry(persister, Arc::new(AtomicU32::new(0)));
+        let result = registry
+            .resume(Uuid::new_v4(), "missing", Uuid::new_v4())
+            .await;
+        assert_eq!(
+            Err(RegistryError::UnknownDefinition("missing".to_string())),
+            result
+        );
+    }
+}
@@ -1,12 +1,28 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::SystemTime,
+};
 
 use uuid::Uuid;
 
+use super::step_key::StepKey;
+
 #[derive(Debug, Clone)]
 pub struct SagaState {
     pub id: Uuid,
-    pub states: BTreeMap<u8, String>,
+    pub states: BTreeMap<StepKey, String>,
+    pub compensations: BTreeMap<StepKey, String>,
+    pub attempts: BTreeMap<StepKey, u32>,
+    /// Steps whose operation was invoked, a started step without a state might have happened
+    pub started: BTreeSet<StepKey>,
+    pub deadline: Option<SystemTime>,
     pub cancelled: bool,
+    /// Cancellation requested through `StepPersister::cancel`
+    pub cancel_requested: bool,
+    /// Serialized payloads delivered through `StepPersister::signal` by signal name
+    pub signals: BTreeMap<String, String>,
+    /// Version of the definition which started the saga
+    pub version: Option<u32>,
 }
 
 impl SagaState {
@@ -14,10 +30,21 @@ impl SagaState {
         Self {
             id,
             states: Default::default(),
+            compensations: Default::default(),
+            attempts: Default::default(),
+            started: Default::default(),
+            deadline: None,
             cancelled: false,
+            cancel_requested: false,
+            signals: Default::default(),
+            version: None,
         }
     }
-    pub fn last_step(&self) -> u8 {
-        self.states.last_key_value().map(|(k, _)| *k).unwrap_or(0)
+    pub fn last_step(&self) -> u32 {
+        self.states
+            .keys()
+            .filter_map(StepKey::index)
+            .max()
+            .unwrap_or(0)
     }
 }
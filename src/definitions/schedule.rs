@@ -0,0 +1,268 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::persisters::persister::{PersistError, StepPersister};
+
+/// Saga started with the serialized data once its next occurrence is due,
+/// recurring schedules move on to the next occurrence of their cron expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    pub id: Uuid,
+    /// Name of the definition in the `SagaRegistry`
+    pub name: String,
+    pub data: String,
+    /// Expression with seconds, evaluated in UTC, e.g. `0 30 9 * * Mon-Fri *`.
+    /// One-off schedules have none
+    pub cron: Option<String>,
+    pub next_at: SystemTime,
+}
+
+impl Schedule {
+    /// Starts the saga once at the time
+    pub fn at(
+        id: Uuid,
+        name: impl Into<String>,
+        data: &impl Serialize,
+        at: SystemTime,
+    ) -> Result<Self, PersistError> {
+        Ok(Self {
+            id,
+            name: name.into(),
+            data: serde_json::to_string(data)?,
+            cron: None,
+            next_at: at,
+        })
+    }
+
+    /// Starts the saga on every occurrence of the cron expression from now on
+    pub fn cron(
+        id: Uuid,
+        name: impl Into<String>,
+        data: &impl Serialize,
+        expression: impl Into<String>,
+    ) -> Result<Self, PersistError> {
+        let expression = expression.into();
+        let next_at = next_occurrence(&expression, SystemTime::now())?
+            .ok_or_else(|| PersistError::InvalidSchedule(format!("{expression} never occurs")))?;
+        Ok(Self {
+            id,
+            name: name.into(),
+            data: serde_json::to_string(data)?,
+            cron: Some(expression),
+            next_at,
+        })
+    }
+
+    /// Id of the saga started for the occurrence, the same on every worker
+    pub fn occurrence_id(&self, at: SystemTime) -> Uuid {
+        let millis = at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Uuid::new_v5(&self.id, millis.to_string().as_bytes())
+    }
+
+    /// Occurrences missed before the time are skipped
+    pub fn next_after(&self, at: SystemTime) -> Result<Option<SystemTime>, PersistError> {
+        match &self.cron {
+            Some(expression) => next_occurrence(expression, at),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(feature = "cron")]
+fn next_occurrence(
+    expression: &str,
+    after: SystemTime,
+) -> Result<Option<SystemTime>, PersistError> {
+    use std::str::FromStr;
+
+    let schedule = cron::Schedule::from_str(expression)
+        .map_err(|e| PersistError::InvalidSchedule(format!("{expression}: {e}")))?;
+    Ok(schedule
+        .after(&chrono::DateTime::<chrono::Utc>::from(after))
+        .next()
+        .map(SystemTime::from))
+}
+
+#[cfg(not(feature = "cron"))]
+fn next_occurrence(
+    expression: &str,
+    _after: SystemTime,
+) -> Result<Option<SystemTime>, PersistError> {
+    Err(PersistError::InvalidSchedule(format!(
+        "{expression}: cron feature is disabled"
+    )))
+}
+
+/// Starts a saga for every due schedule, the started sagas are failed so `get_next_failed`
+/// resumes them.
+///
+/// Only the caller advancing a schedule starts its occurrence, so an occurrence starts at most once
+/// even with several workers. A schedule failing to start doesn't stop the others and stays due
+/// for the next call, returns the id of the started saga or the error by schedule id
+pub async fn start_due<P: StepPersister>(
+    persister: &P,
+    now: SystemTime,
+) -> Result<Vec<(Uuid, Result<Uuid, PersistError>)>, PersistError> {
+    let mut results = Vec::new();
+    for schedule in persister.get_due_schedules(now).await? {
+        let schedule_id = schedule.id;
+        match start_occurrence(persister, schedule, now).await {
+            Ok(Some(id)) => results.push((schedule_id, Ok(id))),
+            Ok(None) => {}
+            Err(e) => results.push((schedule_id, Err(e))),
+        }
+    }
+    Ok(results)
+}
+
+// none if another caller advanced the schedule first
+async fn start_occurrence<P: StepPersister>(
+    persister: &P,
+    schedule: Schedule,
+    now: SystemTime,
+) -> Result<Option<Uuid>, PersistError> {
+    let next_at = schedule.next_after(now.max(schedule.next_at))?;
+    let (schedule_id, name) = (schedule.id, schedule.name.clone());
+    match persister.start_occurrence(schedule, next_at).await {
+        Ok(id) => {
+            log::trace!("started {name} {id} from schedule {schedule_id}");
+            Ok(Some(id))
+        }
+        Err(PersistError::Locked) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        definitions::step_key::StepKey,
+        persisters::{
+            in_memory::InMemoryPersister,
+            persister::{LockScope, LockType},
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_occurrence_ids_are_deterministic() {
+        let at = UNIX_EPOCH + Duration::from_secs(60);
+        let schedule = Schedule::at(Uuid::new_v4(), "test", &1, at).unwrap();
+        assert_eq!(schedule.occurrence_id(at), schedule.occurrence_id(at));
+        assert_ne!(
+            schedule.occurrence_id(at),
+            schedule.occurrence_id(at + Duration::from_secs(1))
+        );
+    }
+
+    #[cfg(feature = "cron")]
+    #[test]
+    fn test_cron_schedule_skips_missed_occurrences() {
+        let schedule = Schedule::cron(Uuid::new_v4(), "test", &1, "0 0 * * * * *").unwrap();
+        let start = UNIX_EPOCH + Duration::from_secs(3600 * 5 + 10);
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(3600 * 6)),
+            schedule.next_after(start).unwrap()
+        );
+        assert!(Schedule::cron(Uuid::new_v4(), "test", &1, "every hour").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_due_schedule_starts_once() {
+        let persister = InMemoryPersister::new(Duration::from_secs(5));
+        let now = SystemTime::now();
+        let schedule = Schedule::at(Uuid::new_v4(), "test", &7, now).unwrap();
+        persister.store_schedule(schedule.clone()).await.unwrap();
+        persister
+            .store_schedule(
+                Schedule::at(Uuid::new_v4(), "later", &7, now + Duration::from_secs(60)).unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let other = persister.clone();
+        let (first, second) = tokio::join!(start_due(&persister, now), start_due(&other, now));
+        let started: Vec<_> = first
+            .unwrap()
+            .into_iter()
+            .chain(second.unwrap())
+            .map(|(schedule_id, result)| (schedule_id, result.unwrap()))
+            .collect();
+        assert_eq!(vec![(schedule.id, schedule.occurrence_id(now))], started);
+        assert!(start_due(&persister, now).await.unwrap().is_empty());
+
+        let started: Vec<_> = started.into_iter().map(|(_, id)| id).collect();
+        let saga = persister.retrieve(started[0]).await.unwrap();
+        assert_eq!(Some(&"7".to_string()), saga.states.get(&StepKey::Index(0)));
+        let (id, name, _) = persister
+            .get_next_failed(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((started[0], "test".to_string()), (id, name));
+    }
+
+    #[tokio::test]
+    async fn test_failing_schedule_does_not_stop_others() {
+        let persister = InMemoryPersister::new(Duration::from_secs(5));
+        let now = SystemTime::now();
+        let failing = Schedule::at(Uuid::new_v4(), "failing", &7, now).unwrap();
+        let starting = Schedule::at(Uuid::new_v4(), "starting", &7, now).unwrap();
+        // occurrence of the failing schedule was cancelled before it started
+        persister
+            .lock(
+                LockScope::from_id(failing.occurrence_id(now), "failing".to_string()),
+                LockType::Cancelled,
+            )
+            .await
+            .unwrap();
+        persister.store_schedule(failing.clone()).await.unwrap();
+        persister.store_schedule(starting.clone()).await.unwrap();
+
+        let mut results = start_due(&persister, now).await.unwrap();
+        results.sort_by_key(|(schedule_id, _)| *schedule_id != failing.id);
+        assert!(matches!(
+            results.as_slice(),
+            [(f, Err(PersistError::Cancelled)), (s, Ok(id))]
+                if *f == failing.id && *s == starting.id && *id == starting.occurrence_id(now)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_schedule_stays_due_until_its_occurrence_starts() {
+        let persister = InMemoryPersister::new(Duration::from_millis(10));
+        let now = SystemTime::now();
+        let schedule = Schedule::at(Uuid::new_v4(), "test", &7, now).unwrap();
+        persister.store_schedule(schedule.clone()).await.unwrap();
+        // another executor still holds the occurrence
+        persister
+            .lock(
+                LockScope::from_id(schedule.occurrence_id(now), "test".to_string()),
+                LockType::Initial,
+            )
+            .await
+            .unwrap();
+
+        assert!(start_due(&persister, now).await.unwrap().is_empty());
+        assert_eq!(1, persister.get_due_schedules(now).await.unwrap().len());
+
+        // let the lock taken above expire
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let started: Vec<_> = start_due(&persister, now)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(schedule_id, result)| (schedule_id, result.unwrap()))
+            .collect();
+        assert_eq!(vec![(schedule.id, schedule.occurrence_id(now))], started);
+        assert!(persister.get_due_schedules(now).await.unwrap().is_empty());
+    }
+}
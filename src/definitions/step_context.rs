@@ -0,0 +1,53 @@
+use uuid::Uuid;
+
+use super::step_key::StepKey;
+
+/// Passed to operations of `step_with_context`, the same on every execution of the step.
+///
+/// Executors taking over a saga re-execute an unfinished step with the same context, so
+/// services deduplicating requests by the idempotency key perform the side effect once.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StepContext {
+    pub saga_id: Uuid,
+    pub step: StepKey,
+    pub idempotency_key: Uuid,
+}
+
+impl StepContext {
+    pub fn new(saga_id: Uuid, step: StepKey) -> Self {
+        Self {
+            idempotency_key: Uuid::new_v5(&saga_id, format!("idempotency/{step}").as_bytes()),
+            saga_id,
+            step,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::persisters::persister::LockScope;
+
+    use super::*;
+
+    #[test]
+    fn test_idempotency_key_depends_on_saga_and_step() {
+        let id = Uuid::new_v4();
+        let context = StepContext::new(id, StepKey::Index(1));
+        assert_eq!(context, StepContext::new(id, StepKey::Index(1)));
+        assert_ne!(
+            context.idempotency_key,
+            StepContext::new(id, StepKey::Index(2)).idempotency_key
+        );
+        assert_ne!(
+            context.idempotency_key,
+            StepContext::new(Uuid::new_v4(), StepKey::Index(1)).idempotency_key
+        );
+        // keys must not collide with ids of child sagas derived from the same step
+        assert_ne!(
+            context.idempotency_key,
+            LockScope::from_id(id, "test".to_string())
+                .child(&StepKey::Index(1))
+                .id
+        );
+    }
+}
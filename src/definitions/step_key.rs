@@ -0,0 +1,62 @@
+use std::fmt;
+
+/// Key a step result is persisted under.
+///
+/// Steps are keyed by their position unless they are named, numeric strings are always indexes.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StepKey {
+    Index(u32),
+    Name(String),
+}
+
+impl StepKey {
+    pub fn index(&self) -> Option<u32> {
+        match self {
+            Self::Index(index) => Some(*index),
+            Self::Name(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for StepKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "{index}"),
+            Self::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+impl From<u32> for StepKey {
+    fn from(index: u32) -> Self {
+        Self::Index(index)
+    }
+}
+
+impl From<String> for StepKey {
+    fn from(key: String) -> Self {
+        key.parse().map(Self::Index).unwrap_or(Self::Name(key))
+    }
+}
+
+impl From<&str> for StepKey {
+    fn from(key: &str) -> Self {
+        Self::from(key.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_round_trip() {
+        for key in [
+            StepKey::Index(300),
+            StepKey::Name("create_ticket".to_string()),
+        ] {
+            assert_eq!(key, StepKey::from(key.to_string()));
+        }
+        assert_eq!(StepKey::Index(7), StepKey::from("7"));
+    }
+}
@@ -1,3 +1,5 @@
 pub mod definitions;
 pub mod helpers;
 pub mod persisters;
+#[cfg(feature = "worker")]
+pub mod worker;
@@ -1,28 +1,167 @@
-use std::time::Duration;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
 
 use uuid::Uuid;
 
-use crate::definitions::saga_state::SagaState;
+use crate::definitions::{saga_state::SagaState, schedule::Schedule, step_key::StepKey};
 
-use super::persister::{LockScope, LockType, PersistError, StepPersister};
+use super::persister::{
+    LockScope, LockType, PersistError, SagaSelector, StepPersister, TransactionalPersister,
+};
 
 #[derive(Default, Clone)]
 pub struct Blackhole {}
 
 #[async_trait::async_trait]
 impl StepPersister for Blackhole {
-    async fn lock(&self, _scope: LockScope, _lock_type: LockType) -> Result<(), PersistError> {
-        Ok(())
+    async fn lock(&self, _scope: LockScope, _lock_type: LockType) -> Result<u64, PersistError> {
+        Ok(0)
     }
 
     async fn retrieve(&self, _id: Uuid) -> Result<SagaState, PersistError> {
         Err(PersistError::NotFound)
     }
 
-    async fn store(&self, _id: Uuid, _step: u8, _state: String) -> Result<(), PersistError> {
+    async fn store(
+        &self,
+        _id: Uuid,
+        _token: u64,
+        _step: StepKey,
+        _state: String,
+    ) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn store_compensation(
+        &self,
+        _id: Uuid,
+        _token: u64,
+        _step: StepKey,
+        _state: String,
+    ) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn store_cancelled(&self, _id: Uuid, _token: u64) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn store_started(
+        &self,
+        _id: Uuid,
+        _token: u64,
+        _step: StepKey,
+    ) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn store_attempt(
+        &self,
+        _id: Uuid,
+        _token: u64,
+        _step: StepKey,
+        _attempt: u32,
+    ) -> Result<(), PersistError> {
         Ok(())
     }
 
+    async fn store_deadline(
+        &self,
+        _id: Uuid,
+        _token: u64,
+        _deadline: SystemTime,
+    ) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn store_version(
+        &self,
+        _id: Uuid,
+        _token: u64,
+        _version: u32,
+    ) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn store_migration(
+        &self,
+        _id: Uuid,
+        _token: u64,
+        _version: u32,
+        _states: BTreeMap<StepKey, String>,
+    ) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn renew(&self, _scope: LockScope) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn cancel(&self, _id: Uuid) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn is_cancel_requested(&self, _id: Uuid) -> Result<bool, PersistError> {
+        Ok(false)
+    }
+
+    async fn pause(&self, _selector: SagaSelector) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn unpause(&self, _selector: SagaSelector) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn is_paused(&self, _id: Uuid, _name: &str) -> Result<bool, PersistError> {
+        Ok(false)
+    }
+
+    async fn requested_interruptions(
+        &self,
+        _id: Uuid,
+        _name: &str,
+    ) -> Result<(bool, bool), PersistError> {
+        Ok((false, false))
+    }
+
+    async fn signal(&self, _id: Uuid, _name: &str, _payload: String) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn store_consumed_signal(
+        &self,
+        _id: Uuid,
+        _token: u64,
+        _step: StepKey,
+        _state: String,
+        _name: &str,
+    ) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn store_schedule(&self, _schedule: Schedule) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn remove_schedule(&self, _id: Uuid) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn get_due_schedules(&self, _now: SystemTime) -> Result<Vec<Schedule>, PersistError> {
+        Ok(Vec::new())
+    }
+
+    async fn start_occurrence(
+        &self,
+        _schedule: Schedule,
+        _next_at: Option<SystemTime>,
+    ) -> Result<Uuid, PersistError> {
+        Err(PersistError::NotFound)
+    }
+
     async fn get_next_failed(
         &self,
         _for_duration: Duration,
@@ -30,3 +169,22 @@ impl StepPersister for Blackhole {
         Err(PersistError::NotFound)
     }
 }
+
+#[async_trait::async_trait]
+impl TransactionalPersister for Blackhole {
+    type Transaction = ();
+
+    async fn begin(&self, _id: Uuid, _token: u64) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    async fn commit_step(
+        &self,
+        _transaction: (),
+        _id: Uuid,
+        _step: StepKey,
+        _state: String,
+    ) -> Result<(), PersistError> {
+        Ok(())
+    }
+}
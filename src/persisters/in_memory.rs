@@ -1,20 +1,28 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    sync::{Arc, RwLock},
-    time::{Duration, Instant},
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use uuid::Uuid;
 
-use crate::definitions::saga_state::SagaState;
+use crate::definitions::{saga_state::SagaState, schedule::Schedule, step_key::StepKey};
 
-use super::persister::{LockScope, LockType, PersistError, StepPersister};
+use super::persister::{
+    LockScope, LockType, PersistError, SagaSelector, StepPersister, TransactionalPersister,
+};
 
 #[derive(Debug, Clone)]
 pub struct InMemoryPersister {
     sagas: Arc<RwLock<HashMap<Uuid, SagaState>>>,
     locks: Arc<RwLock<HashMap<Uuid, ExecutingContext>>>,
     lock_timeout: Duration,
+    tokens: Arc<AtomicU64>,
+    paused: Arc<RwLock<HashSet<SagaSelector>>>,
+    schedules: Arc<RwLock<HashMap<Uuid, Schedule>>>,
 }
 
 impl InMemoryPersister {
@@ -23,100 +31,369 @@ impl InMemoryPersister {
             sagas: Arc::new(RwLock::new(Default::default())),
             locks: Arc::new(RwLock::new(Default::default())),
             lock_timeout,
+            tokens: Default::default(),
+            paused: Default::default(),
+            schedules: Default::default(),
         }
     }
-}
-
-#[async_trait::async_trait]
-impl StepPersister for InMemoryPersister {
-    async fn retrieve(&self, id: Uuid) -> Result<SagaState, PersistError> {
-        self.sagas
-            .read()
-            .expect("sagas lock")
-            .get(&id)
-            .cloned()
-            .ok_or(PersistError::NotFound)
-    }
 
-    async fn store(&self, id: Uuid, step: u8, state: String) -> Result<(), PersistError> {
-        let mut sagas = self.sagas.write().expect("sagas lock");
-        let entry = sagas.entry(id);
-        match entry {
-            Entry::Occupied(mut s) => {
-                s.get_mut().states.insert(step, state);
-            }
-            Entry::Vacant(e) => {
-                e.insert(SagaState {
-                    id,
-                    states: vec![(step, state)].into_iter().collect(),
-                    cancelled: false,
-                });
-            }
-        };
+    // lock table stays locked during the write, so no other executor can take over meanwhile
+    fn write(
+        &self,
+        id: Uuid,
+        token: u64,
+        write: impl FnOnce(&mut SagaState),
+    ) -> Result<(), PersistError> {
+        let locks = self.locks.read().expect("persister locks lock");
+        if locks.get(&id).map(|c| c.token) != Some(token) {
+            return Err(PersistError::Fenced);
+        }
+        write(
+            self.sagas
+                .write()
+                .expect("sagas lock")
+                .entry(id)
+                .or_insert_with(|| SagaState::new(id)),
+        );
         Ok(())
     }
 
-    async fn lock(&self, scope: LockScope, lock_type: LockType) -> Result<(), PersistError> {
-        let insert = if let Some(context) = self
-            .locks
-            .read()
-            .expect("persister locks lock")
-            .get(&scope.id)
-        {
+    // callers holding the schedules lock take saga locks without awaiting
+    fn take_lock(&self, scope: LockScope, lock_type: LockType) -> Result<u64, PersistError> {
+        let mut locks = self.locks.write().expect("persister locks lock");
+        let current = locks.get(&scope.id);
+        if scope.token.is_some() && current.map(|c| c.token) != scope.token {
+            return Err(PersistError::Fenced);
+        }
+        if current.is_some_and(|c| matches!(c.lock_type, LockType::Cancelled)) {
+            return Err(PersistError::Cancelled);
+        }
+        let insert = if let Some(context) = current {
             scope.executor_id == context.executor_id
-                || matches!(context.lock_type, LockType::Failed)
-                || context.instant_started.elapsed() > self.lock_timeout
+                || match context.lock_type {
+                    LockType::Failed | LockType::Paused => true,
+                    LockType::Waiting => context.signalled,
+                    LockType::Sleeping(wake_at) => wake_at <= SystemTime::now(),
+                    _ => context.instant_started.elapsed() > self.lock_timeout,
+                }
         } else {
             true
         };
+        // a signal arriving before the saga parked must still wake it up
+        let signalled =
+            matches!(lock_type, LockType::Waiting) && current.is_some_and(|c| c.signalled);
 
         if insert {
+            let token = self.tokens.fetch_add(1, Ordering::SeqCst) + 1;
             if matches!(lock_type, LockType::Finished) {
-                self.locks
-                    .write()
-                    .expect("persister locks lock")
-                    .remove(&scope.id);
+                locks.remove(&scope.id);
                 self.sagas
                     .write()
                     .expect("persister locks lock")
                     .remove(&scope.id);
             } else {
-                self.locks.write().expect("persister locks lock").insert(
+                locks.insert(
                     scope.id,
                     ExecutingContext {
                         executor_id: scope.executor_id,
                         lock_type,
                         instant_started: Instant::now(),
                         name: scope.name,
+                        parent: scope.parent,
+                        token,
+                        signalled,
                     },
                 );
             }
-            Ok(())
+            Ok(token)
         } else {
             Err(PersistError::Locked)
         }
     }
+}
+
+#[async_trait::async_trait]
+impl StepPersister for InMemoryPersister {
+    async fn retrieve(&self, id: Uuid) -> Result<SagaState, PersistError> {
+        self.sagas
+            .read()
+            .expect("sagas lock")
+            .get(&id)
+            .cloned()
+            .ok_or(PersistError::NotFound)
+    }
+
+    async fn store(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError> {
+        self.write(id, token, |saga| {
+            saga.states.insert(step, state);
+        })
+    }
+
+    async fn store_compensation(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError> {
+        self.write(id, token, |saga| {
+            saga.compensations.insert(step, state);
+        })
+    }
+
+    async fn store_cancelled(&self, id: Uuid, token: u64) -> Result<(), PersistError> {
+        self.write(id, token, |saga| saga.cancelled = true)
+    }
+
+    async fn store_started(&self, id: Uuid, token: u64, step: StepKey) -> Result<(), PersistError> {
+        self.write(id, token, |saga| {
+            saga.started.insert(step);
+        })
+    }
+
+    async fn store_attempt(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        attempt: u32,
+    ) -> Result<(), PersistError> {
+        self.write(id, token, |saga| {
+            saga.attempts.insert(step, attempt);
+        })
+    }
+
+    async fn store_deadline(
+        &self,
+        id: Uuid,
+        token: u64,
+        deadline: SystemTime,
+    ) -> Result<(), PersistError> {
+        self.write(id, token, |saga| saga.deadline = Some(deadline))
+    }
+
+    async fn store_version(&self, id: Uuid, token: u64, version: u32) -> Result<(), PersistError> {
+        self.write(id, token, |saga| saga.version = Some(version))
+    }
+
+    async fn store_migration(
+        &self,
+        id: Uuid,
+        token: u64,
+        version: u32,
+        states: BTreeMap<StepKey, String>,
+    ) -> Result<(), PersistError> {
+        self.write(id, token, |saga| {
+            saga.states = states;
+            saga.version = Some(version);
+        })
+    }
+
+    async fn lock(&self, scope: LockScope, lock_type: LockType) -> Result<u64, PersistError> {
+        self.take_lock(scope, lock_type)
+    }
+
+    async fn renew(&self, scope: LockScope) -> Result<(), PersistError> {
+        match self
+            .locks
+            .write()
+            .expect("persister locks lock")
+            .get_mut(&scope.id)
+        {
+            Some(context)
+                if context.executor_id == scope.executor_id
+                    && scope.token.is_none_or(|t| t == context.token) =>
+            {
+                context.instant_started = Instant::now();
+                Ok(())
+            }
+            _ => Err(PersistError::LockLost),
+        }
+    }
+
+    async fn cancel(&self, id: Uuid) -> Result<(), PersistError> {
+        self.sagas
+            .write()
+            .expect("sagas lock")
+            .entry(id)
+            .or_insert_with(|| SagaState::new(id))
+            .cancel_requested = true;
+        Ok(())
+    }
+
+    async fn is_cancel_requested(&self, id: Uuid) -> Result<bool, PersistError> {
+        Ok(self
+            .sagas
+            .read()
+            .expect("sagas lock")
+            .get(&id)
+            .is_some_and(|s| s.cancel_requested))
+    }
+
+    async fn pause(&self, selector: SagaSelector) -> Result<(), PersistError> {
+        self.paused.write().expect("paused lock").insert(selector);
+        Ok(())
+    }
+
+    async fn unpause(&self, selector: SagaSelector) -> Result<(), PersistError> {
+        self.paused.write().expect("paused lock").remove(&selector);
+        Ok(())
+    }
+
+    async fn is_paused(&self, id: Uuid, name: &str) -> Result<bool, PersistError> {
+        let paused = self.paused.read().expect("paused lock");
+        Ok(paused.contains(&SagaSelector::Id(id))
+            || paused.contains(&SagaSelector::Name(name.to_string())))
+    }
+
+    async fn requested_interruptions(
+        &self,
+        id: Uuid,
+        name: &str,
+    ) -> Result<(bool, bool), PersistError> {
+        Ok((
+            self.is_cancel_requested(id).await?,
+            self.is_paused(id, name).await?,
+        ))
+    }
+
+    async fn signal(&self, id: Uuid, name: &str, payload: String) -> Result<(), PersistError> {
+        self.sagas
+            .write()
+            .expect("sagas lock")
+            .entry(id)
+            .or_insert_with(|| SagaState::new(id))
+            .signals
+            .insert(name.to_string(), payload);
+        let mut locks = self.locks.write().expect("persister locks lock");
+        // parents parked by the waiting child are woken up with it
+        let mut next = Some(id);
+        while let Some(context) = next.and_then(|id| locks.get_mut(&id)) {
+            context.signalled = true;
+            next = context.parent;
+        }
+        Ok(())
+    }
+
+    async fn store_consumed_signal(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+        name: &str,
+    ) -> Result<(), PersistError> {
+        self.write(id, token, |saga| {
+            saga.states.insert(step, state);
+            saga.signals.remove(name);
+        })
+    }
+
+    async fn store_schedule(&self, schedule: Schedule) -> Result<(), PersistError> {
+        self.schedules
+            .write()
+            .expect("schedules lock")
+            .insert(schedule.id, schedule);
+        Ok(())
+    }
+
+    async fn remove_schedule(&self, id: Uuid) -> Result<(), PersistError> {
+        self.schedules.write().expect("schedules lock").remove(&id);
+        Ok(())
+    }
+
+    async fn get_due_schedules(&self, now: SystemTime) -> Result<Vec<Schedule>, PersistError> {
+        Ok(self
+            .schedules
+            .read()
+            .expect("schedules lock")
+            .values()
+            .filter(|s| s.next_at <= now)
+            .cloned()
+            .collect())
+    }
+
+    async fn start_occurrence(
+        &self,
+        schedule: Schedule,
+        next_at: Option<SystemTime>,
+    ) -> Result<Uuid, PersistError> {
+        // the schedule stays locked until the occurrence is created, so it starts once
+        let mut schedules = self.schedules.write().expect("schedules lock");
+        if schedules
+            .get(&schedule.id)
+            .is_none_or(|s| s.next_at != schedule.next_at)
+        {
+            return Err(PersistError::Locked);
+        }
+        let scope = LockScope::from_id(schedule.occurrence_id(schedule.next_at), schedule.name);
+        let token = self.take_lock(scope.clone(), LockType::Initial)?;
+        self.write(scope.id, token, |saga| {
+            saga.states.insert(StepKey::Index(0), schedule.data);
+        })?;
+        self.take_lock(
+            LockScope {
+                token: Some(token),
+                ..scope.clone()
+            },
+            LockType::Failed,
+        )?;
+        match next_at {
+            Some(next_at) => {
+                if let Some(advanced) = schedules.get_mut(&schedule.id) {
+                    advanced.next_at = next_at;
+                }
+            }
+            None => {
+                schedules.remove(&schedule.id);
+            }
+        }
+        Ok(scope.id)
+    }
 
     async fn get_next_failed(
         &self,
         duration: Duration,
     ) -> Result<Option<(Uuid, String, Uuid)>, PersistError> {
         let new_executor = Uuid::new_v4();
-        let scope_result = self
-            .locks
-            .read()
-            .expect("persister locks lock")
-            .iter()
-            .find(|(_, context)| match context.lock_type {
-                LockType::Failed => true,
-                LockType::Finished => false,
-                _ => context.instant_started.elapsed() > duration,
-            })
-            .map(|(key, context)| LockScope {
-                id: *key,
-                executor_id: new_executor,
-                name: context.name.clone(),
-            });
+        let scope_result = {
+            let paused = self.paused.read().expect("paused lock");
+            let locks = self.locks.read().expect("persister locks lock");
+            let is_paused = |id: &Uuid, context: &ExecutingContext| {
+                paused.contains(&SagaSelector::Id(*id))
+                    || paused.contains(&SagaSelector::Name(context.name.clone()))
+            };
+            locks
+                .iter()
+                .filter(|(_, context)| context.parent.is_none())
+                // a paused child keeps its parent paused
+                .filter(|(key, context)| {
+                    !is_paused(key, context)
+                        && !locks
+                            .iter()
+                            .any(|(id, c)| c.parent == Some(**key) && is_paused(id, c))
+                })
+                .find(|(_, context)| match context.lock_type {
+                    LockType::Failed | LockType::Paused => true,
+                    LockType::Waiting => context.signalled,
+                    LockType::Sleeping(wake_at) => wake_at <= SystemTime::now(),
+                    LockType::Finished | LockType::Cancelled => false,
+                    _ => context.instant_started.elapsed() > duration,
+                })
+                .map(|(key, context)| LockScope {
+                    id: *key,
+                    executor_id: new_executor,
+                    name: context.name.clone(),
+                    parent: None,
+                    token: None,
+                })
+        };
         if let Some(scope) = scope_result {
             self.lock(scope.clone(), LockType::Retry).await?;
             Ok(Some((scope.id, scope.name, new_executor)))
@@ -126,12 +403,46 @@ impl StepPersister for InMemoryPersister {
     }
 }
 
+/// Holds the token of the lock, the step result is the only write of the transaction
+#[derive(Debug)]
+pub struct InMemoryTransaction {
+    token: u64,
+}
+
+#[async_trait::async_trait]
+impl TransactionalPersister for InMemoryPersister {
+    type Transaction = InMemoryTransaction;
+
+    async fn begin(&self, id: Uuid, token: u64) -> Result<InMemoryTransaction, PersistError> {
+        let locks = self.locks.read().expect("persister locks lock");
+        if locks.get(&id).map(|c| c.token) != Some(token) {
+            return Err(PersistError::Fenced);
+        }
+        Ok(InMemoryTransaction { token })
+    }
+
+    async fn commit_step(
+        &self,
+        transaction: InMemoryTransaction,
+        id: Uuid,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError> {
+        self.write(id, transaction.token, |saga| {
+            saga.states.insert(step, state);
+        })
+    }
+}
+
 #[derive(Debug)]
 struct ExecutingContext {
     executor_id: Uuid,
     lock_type: LockType,
     instant_started: Instant,
     name: String,
+    parent: Option<Uuid>,
+    token: u64,
+    signalled: bool,
 }
 
 #[cfg(test)]
@@ -147,6 +458,8 @@ mod tests {
             id: Uuid::new_v4(),
             executor_id: Uuid::new_v4(),
             name: "test1".to_string(),
+            parent: None,
+            token: None,
         };
         persister
             .lock(scope.clone(), LockType::Initial)
@@ -174,11 +487,15 @@ mod tests {
             id: Uuid::new_v4(),
             executor_id: Uuid::new_v4(),
             name: "test1".to_string(),
+            parent: None,
+            token: None,
         };
         let scope2 = LockScope {
             id: scope1.id,
             executor_id: Uuid::new_v4(),
             name: "test1".to_string(),
+            parent: None,
+            token: None,
         };
         persister
             .lock(scope1.clone(), LockType::Initial)
@@ -196,4 +513,191 @@ mod tests {
         let result = persister.lock(scope1.clone(), LockType::Executing).await;
         assert!(result.is_ok(), "{result:?}");
     }
+
+    #[tokio::test]
+    async fn test_child_is_not_returned_as_failed() {
+        let persister = InMemoryPersister::new(Duration::from_millis(10));
+        let parent = LockScope::from_id(Uuid::new_v4(), "parent".to_string());
+        let child = parent.child(&StepKey::Index(1));
+        persister.lock(child, LockType::Failed).await.unwrap();
+
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert!(result.is_none(), "{result:?}");
+
+        persister
+            .lock(parent.clone(), LockType::Failed)
+            .await
+            .unwrap();
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert_eq!(
+            Some((parent.id, parent.name)),
+            result.map(|(id, name, _)| (id, name))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_renew_only_by_lock_holder() {
+        let persister = InMemoryPersister::new(Duration::from_millis(50));
+        let scope = LockScope::from_id(Uuid::new_v4(), "test1".to_string());
+        let other = LockScope::from_id(scope.id, "test1".to_string());
+        persister
+            .lock(scope.clone(), LockType::Executing)
+            .await
+            .unwrap();
+
+        sleep(Duration::from_millis(30));
+        persister.renew(scope.clone()).await.unwrap();
+        sleep(Duration::from_millis(30));
+        let result = persister.lock(other.clone(), LockType::Executing).await;
+        assert!(matches!(result, Err(PersistError::Locked)), "{result:?}");
+
+        sleep(Duration::from_millis(60));
+        persister.lock(other, LockType::Executing).await.unwrap();
+        let result = persister.renew(scope).await;
+        assert!(matches!(result, Err(PersistError::LockLost)), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_stale_token_is_fenced() {
+        let persister = InMemoryPersister::new(Duration::from_millis(10));
+        let scope = LockScope::from_id(Uuid::new_v4(), "test1".to_string());
+        let other = LockScope::from_id(scope.id, "test1".to_string());
+        let token = persister
+            .lock(scope.clone(), LockType::Executing)
+            .await
+            .unwrap();
+        persister
+            .store(scope.id, token, StepKey::Index(0), "1".to_string())
+            .await
+            .unwrap();
+
+        sleep(Duration::from_millis(13));
+        let newer = persister.lock(other, LockType::Executing).await.unwrap();
+        assert!(newer > token);
+
+        let result = persister
+            .store(scope.id, token, StepKey::Index(1), "2".to_string())
+            .await;
+        assert!(matches!(result, Err(PersistError::Fenced)), "{result:?}");
+        let stale = LockScope {
+            token: Some(token),
+            ..scope
+        };
+        let result = persister.lock(stale, LockType::Failed).await;
+        assert!(matches!(result, Err(PersistError::Fenced)), "{result:?}");
+        let saga = persister.retrieve(scope.id).await.unwrap();
+        assert!(!saga.states.contains_key(&StepKey::Index(1)));
+    }
+
+    #[tokio::test]
+    async fn test_paused_saga_is_not_returned_as_failed() {
+        let persister = InMemoryPersister::new(Duration::from_millis(10));
+        let scope = LockScope::from_id(Uuid::new_v4(), "report".to_string());
+        persister
+            .lock(scope.clone(), LockType::Failed)
+            .await
+            .unwrap();
+        persister
+            .pause(SagaSelector::Name(scope.name.clone()))
+            .await
+            .unwrap();
+        persister.pause(SagaSelector::Id(scope.id)).await.unwrap();
+
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert!(result.is_none(), "{result:?}");
+        persister
+            .unpause(SagaSelector::Name(scope.name.clone()))
+            .await
+            .unwrap();
+        assert!(persister.is_paused(scope.id, &scope.name).await.unwrap());
+
+        persister.unpause(SagaSelector::Id(scope.id)).await.unwrap();
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert_eq!(Some(scope.id), result.map(|(id, _, _)| id));
+    }
+
+    #[tokio::test]
+    async fn test_waiting_saga_is_returned_once_signalled() {
+        let persister = InMemoryPersister::new(Duration::from_millis(10));
+        let scope = LockScope::from_id(Uuid::new_v4(), "approval".to_string());
+        persister
+            .lock(scope.clone(), LockType::Waiting)
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(13));
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert!(result.is_none(), "{result:?}");
+        let other = LockScope::from_id(scope.id, scope.name.clone());
+        let result = persister.lock(other, LockType::Executing).await;
+        assert!(matches!(result, Err(PersistError::Locked)), "{result:?}");
+
+        // signal arrives while the saga still executes the waiting step
+        persister
+            .lock(scope.clone(), LockType::Executing)
+            .await
+            .unwrap();
+        persister
+            .signal(scope.id, "approved", "true".to_string())
+            .await
+            .unwrap();
+        persister
+            .lock(scope.clone(), LockType::Waiting)
+            .await
+            .unwrap();
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert_eq!(Some(scope.id), result.map(|(id, _, _)| id));
+        let saga = persister.retrieve(scope.id).await.unwrap();
+        assert_eq!(Some(&"true".to_string()), saga.signals.get("approved"));
+    }
+
+    #[tokio::test]
+    async fn test_sleeping_saga_is_returned_once_awake() {
+        let persister = InMemoryPersister::new(Duration::from_millis(5));
+        let scope = LockScope::from_id(Uuid::new_v4(), "reminder".to_string());
+        let wake_at = SystemTime::now() + Duration::from_millis(30);
+        persister
+            .lock(scope.clone(), LockType::Sleeping(wake_at))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(10));
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert!(result.is_none(), "{result:?}");
+        let other = LockScope::from_id(scope.id, scope.name.clone());
+        let result = persister.lock(other, LockType::Executing).await;
+        assert!(matches!(result, Err(PersistError::Locked)), "{result:?}");
+
+        sleep(Duration::from_millis(25));
+        let result = persister.get_next_failed(Duration::ZERO).await.unwrap();
+        assert_eq!(Some(scope.id), result.map(|(id, _, _)| id));
+    }
+
+    #[tokio::test]
+    async fn test_transaction_is_fenced() {
+        let persister = InMemoryPersister::new(Duration::from_millis(10));
+        let scope = LockScope::from_id(Uuid::new_v4(), "test1".to_string());
+        let token = persister
+            .lock(scope.clone(), LockType::Initial)
+            .await
+            .unwrap();
+        let transaction = persister.begin(scope.id, token).await.unwrap();
+        sleep(Duration::from_millis(20));
+        persister
+            .lock(
+                LockScope::from_id(scope.id, "test1".to_string()),
+                LockType::Retry,
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            persister.begin(scope.id, token).await,
+            Err(PersistError::Fenced)
+        ));
+        assert!(matches!(
+            persister
+                .commit_step(transaction, scope.id, StepKey::Index(1), "1".to_string())
+                .await,
+            Err(PersistError::Fenced)
+        ));
+    }
 }
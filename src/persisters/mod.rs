@@ -1,3 +1,9 @@
 pub mod blackhole;
 pub mod in_memory;
 pub mod persister;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "mysql")]
+pub mod mysql;
@@ -1,25 +1,141 @@
-use std::{error::Error, fmt, time::Duration};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    time::{Duration, SystemTime},
+};
 
 use uuid::Uuid;
 
-use crate::definitions::saga_state::SagaState;
+use crate::definitions::{saga_state::SagaState, schedule::Schedule, step_key::StepKey};
 
 #[async_trait::async_trait]
 pub trait StepPersister: Clone + Send + Sync + 'static {
-    async fn lock(&self, scope: LockScope, lock_type: LockType) -> Result<(), PersistError>;
+    /// Returns a fencing token greater than the tokens of all earlier locks.
+    /// A scope carrying the token of an earlier lock fails with `Fenced`
+    async fn lock(&self, scope: LockScope, lock_type: LockType) -> Result<u64, PersistError>;
+    /// Extends the lock held by the executor of the scope, fails with `LockLost`
+    /// if another executor holds the lock
+    async fn renew(&self, scope: LockScope) -> Result<(), PersistError>;
     async fn retrieve(&self, id: Uuid) -> Result<SagaState, PersistError>;
-    async fn store(&self, id: Uuid, step: u8, state: String) -> Result<(), PersistError>;
+    /// Storing a step again replaces its state.
+    /// Writes fail with `Fenced` unless the token is the one of the current lock
+    async fn store(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError>;
+    async fn store_compensation(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError>;
+    async fn store_cancelled(&self, id: Uuid, token: u64) -> Result<(), PersistError>;
+    /// Marks the step as started before its operation is invoked
+    async fn store_started(&self, id: Uuid, token: u64, step: StepKey) -> Result<(), PersistError>;
+    async fn store_attempt(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        attempt: u32,
+    ) -> Result<(), PersistError>;
+    async fn store_deadline(
+        &self,
+        id: Uuid,
+        token: u64,
+        deadline: SystemTime,
+    ) -> Result<(), PersistError>;
+    async fn store_version(&self, id: Uuid, token: u64, version: u32) -> Result<(), PersistError>;
+    /// Replaces all step states of a saga migrated to the version
+    async fn store_migration(
+        &self,
+        id: Uuid,
+        token: u64,
+        version: u32,
+        states: BTreeMap<StepKey, String>,
+    ) -> Result<(), PersistError>;
+    /// Requests cancellation of the saga, a running saga compensates at its next step
+    /// and a resumed one compensates right away. Cancelled sagas end with `LockType::Cancelled`
+    async fn cancel(&self, id: Uuid) -> Result<(), PersistError>;
+    async fn is_cancel_requested(&self, id: Uuid) -> Result<bool, PersistError>;
+    /// Paused sagas are skipped by `get_next_failed`, a running saga stops at its next step
+    /// and releases its lock as `LockType::Paused`.
+    /// A saga stays paused while either its id or its name is paused
+    async fn pause(&self, selector: SagaSelector) -> Result<(), PersistError>;
+    async fn unpause(&self, selector: SagaSelector) -> Result<(), PersistError>;
+    async fn is_paused(&self, id: Uuid, name: &str) -> Result<bool, PersistError>;
+    /// Whether cancellation is requested for the saga and whether it is paused, in one read
+    async fn requested_interruptions(
+        &self,
+        id: Uuid,
+        name: &str,
+    ) -> Result<(bool, bool), PersistError>;
+    /// Delivers the serialized payload to a saga waiting for the signal with the name,
+    /// the saga becomes eligible for `get_next_failed` once it is `LockType::Waiting`
+    async fn signal(&self, id: Uuid, name: &str, payload: String) -> Result<(), PersistError>;
+    /// Stores the output of the step waiting for the signal and removes the signal in the same
+    /// write, so waiting for the name again needs a new delivery
+    async fn store_consumed_signal(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+        name: &str,
+    ) -> Result<(), PersistError>;
+    /// Storing a schedule again replaces it
+    async fn store_schedule(&self, schedule: Schedule) -> Result<(), PersistError>;
+    async fn remove_schedule(&self, id: Uuid) -> Result<(), PersistError>;
+    /// Schedules with their next occurrence at or before the time
+    async fn get_due_schedules(&self, now: SystemTime) -> Result<Vec<Schedule>, PersistError>;
+    /// Moves the schedule from its occurrence to the next one, removing it without one, and
+    /// stores the saga of the occurrence as failed in the same write, so `get_next_failed` starts
+    /// it. Returns the saga id, fails with `Locked` if the schedule is no longer at the occurrence.
+    /// On any error the schedule stays at the occurrence
+    async fn start_occurrence(
+        &self,
+        schedule: Schedule,
+        next_at: Option<SystemTime>,
+    ) -> Result<Uuid, PersistError>;
     async fn get_next_failed(
         &self,
         for_duration: Duration,
     ) -> Result<Option<(Uuid, String, Uuid)>, PersistError>;
 }
 
+/// Persister writing step results in the transaction of a local step operation,
+/// so the step takes effect and is checkpointed in one commit
+#[async_trait::async_trait]
+pub trait TransactionalPersister: StepPersister {
+    type Transaction: Send + 'static;
+
+    /// Fails with `Fenced` unless the token is the one of the current lock,
+    /// no other executor can take over until the transaction ends
+    async fn begin(&self, id: Uuid, token: u64) -> Result<Self::Transaction, PersistError>;
+    /// Stores the step result in the transaction and commits it
+    async fn commit_step(
+        &self,
+        transaction: Self::Transaction,
+        id: Uuid,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError>;
+}
+
 #[derive(Debug, Clone)]
 pub struct LockScope {
     pub id: Uuid,
     pub executor_id: Uuid,
     pub name: String,
+    /// Set for child sagas, which are resumed through their parent
+    pub parent: Option<Uuid>,
+    /// Fencing token returned by the last lock of this executor, none before the first lock
+    pub token: Option<u64>,
 }
 
 impl LockScope {
@@ -28,8 +144,29 @@ impl LockScope {
             id,
             executor_id: Uuid::new_v4(),
             name,
+            parent: None,
+            token: None,
         }
     }
+
+    /// Scope of a child saga executed as the given step of this saga.
+    /// The id is derived from the parent so a resumed parent finds the same child again
+    pub fn child(&self, step: &StepKey) -> Self {
+        Self {
+            id: Uuid::new_v5(&self.id, step.to_string().as_bytes()),
+            executor_id: self.executor_id,
+            name: format!("{}/{step}", self.name),
+            parent: Some(self.id),
+            token: None,
+        }
+    }
+}
+
+/// Sagas affected by an operation, a single saga or all sagas with the `LockScope` name
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SagaSelector {
+    Id(Uuid),
+    Name(String),
 }
 
 #[derive(Debug)]
@@ -39,12 +176,31 @@ pub enum LockType {
     Finished,
     Initial,
     Retry,
+    /// Saga stopped because it was paused, resumed once unpaused
+    Paused,
+    /// Saga waits for a signal, resumed once the signal arrives
+    Waiting,
+    /// Saga sleeps, resumed once the wake up time passed
+    Sleeping(SystemTime),
+    /// Saga was compensated after an external cancellation or stopped past its deadline,
+    /// it can not be locked anymore
+    Cancelled,
 }
 
 #[derive(Debug)]
 pub enum PersistError {
     Locked,
+    LockLost,
+    /// Write or lock with the token of a lock another executor took over since
+    Fenced,
     NotFound,
+    Cancelled,
+    Paused,
+    Waiting,
+    Sleeping,
+    Timeout,
+    InvalidSchedule(String),
+    IncompatibleVersion(u32, u32),
     Serialization(serde_json::Error),
     Execution(String, String),
 }
@@ -53,7 +209,19 @@ impl fmt::Display for PersistError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Locked => write!(f, "Record is locked"),
+            Self::LockLost => write!(f, "Lock was taken by another executor"),
+            Self::Fenced => write!(f, "Fencing token is stale, saga is locked by a newer lock"),
             Self::NotFound => write!(f, "Record not found"),
+            Self::Cancelled => write!(f, "Saga was cancelled"),
+            Self::Paused => write!(f, "Saga is paused"),
+            Self::Waiting => write!(f, "Saga is waiting for a signal"),
+            Self::Sleeping => write!(f, "Saga is sleeping"),
+            Self::Timeout => write!(f, "Saga timed out"),
+            Self::InvalidSchedule(e) => write!(f, "Invalid schedule {e}"),
+            Self::IncompatibleVersion(stored, expected) => write!(
+                f,
+                "Saga version {stored} is incompatible with definition version {expected}"
+            ),
             Self::Serialization(e) => write!(f, "Failed to serialize: {e}"),
             Self::Execution(e, c) => write!(f, "Failed to execute {c}: {e}"),
         }
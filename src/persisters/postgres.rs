@@ -0,0 +1,1271 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Serialize;
+use sqlx::{Executor, Pool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::definitions::{saga_state::SagaState, schedule::Schedule, step_key::StepKey};
+
+use super::persister::{
+    LockScope, LockType, PersistError, SagaSelector, StepPersister, TransactionalPersister,
+};
+
+const MIGRATION: &str = include_str!("sql/postgres.sql");
+const LEGACY_MIGRATION: &str = include_str!("sql/postgres_legacy.sql");
+// serializes migrations of instances starting at once
+const MIGRATION_LOCK: i64 = 0x7361_6761;
+
+/// Persists sagas in Postgres tables named by the prefix, `saga` in the `public` schema by default
+#[derive(Debug, Clone)]
+pub struct PostgresPersister {
+    pool: Pool<Postgres>,
+    lock_timeout: Duration,
+    schema: String,
+    prefix: String,
+}
+
+impl PostgresPersister {
+    pub fn new(pool: Pool<Postgres>, lock_timeout: Duration) -> Self {
+        Self {
+            pool,
+            lock_timeout,
+            schema: "public".to_string(),
+            prefix: "saga".to_string(),
+        }
+    }
+
+    /// Schema of the tables, `migrate` creates it if missing
+    pub fn schema(mut self, schema: impl Into<String>) -> Self {
+        self.schema = schema.into();
+        self
+    }
+
+    /// Tables are named `{prefix}`, `{prefix}_step`, `{prefix}_lock` and so on
+    pub fn table_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Creates missing tables, safe to run on every start of every instance.
+    /// Tables of the persister the order-ticket example used to ship are converted in place
+    pub async fn migrate(&self) -> Result<(), PersistError> {
+        let fill = |sql: &str| {
+            sql.replace("{schema}", &escape(&self.schema))
+                .replace("{prefix}", &escape(&self.prefix))
+        };
+        let mut tx = self.begin("migration transaction").await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(MIGRATION_LOCK)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "migration lock".to_string()))?;
+        // the legacy lock column is an enum, the current one is text
+        let (legacy,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS (SELECT 1 FROM information_schema.columns
+                WHERE table_schema = $1 AND table_name = $2
+                    AND column_name = 'lock' AND data_type = 'USER-DEFINED')",
+        )
+        .bind(&self.schema)
+        .bind(format!("{}_lock", self.prefix))
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "legacy tables".to_string()))?;
+        if legacy {
+            (&mut *tx)
+                .execute(fill(LEGACY_MIGRATION).as_str())
+                .await
+                .map_err(|e| {
+                    PersistError::Execution(e.to_string(), "legacy migration".to_string())
+                })?;
+        }
+        (&mut *tx)
+            .execute(fill(MIGRATION).as_str())
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "migration".to_string()))?;
+        commit(tx, "migration commit").await
+    }
+
+    /// Locks the saga and stores its initial data in the transaction of the application writes,
+    /// so the saga exists once they commit. Resume it with `continue_from_last_step`
+    pub async fn save_initial_state<S: Serialize + Send + Sync>(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        scope: LockScope,
+        initial_state: &S,
+    ) -> Result<u64, PersistError> {
+        let state = serde_json::to_string(initial_state)?;
+        let token = self.lock_in(tx, scope.clone(), LockType::Initial).await?;
+        self.store_in(tx, scope.id, StepKey::Index(0), state)
+            .await?;
+        Ok(token)
+    }
+
+    fn table(&self, name: &str) -> String {
+        let name = match name {
+            "" => self.prefix.clone(),
+            name => format!("{}_{name}", self.prefix),
+        };
+        format!("\"{}\".\"{}\"", escape(&self.schema), escape(&name))
+    }
+
+    async fn begin(&self, context: &str) -> Result<Transaction<'static, Postgres>, PersistError> {
+        self.pool
+            .begin()
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), context.to_string()))
+    }
+
+    // the saga row stays locked until commit, so no other executor can take over meanwhile
+    async fn begin_fenced(
+        &self,
+        id: Uuid,
+        token: u64,
+    ) -> Result<Transaction<'static, Postgres>, PersistError> {
+        let mut tx = self.begin("fenced transaction").await?;
+        sqlx::query(&format!(
+            "SELECT id FROM {} WHERE id = $1 FOR UPDATE",
+            self.table("")
+        ))
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "lock saga".to_string()))?;
+        let current: Option<(i64,)> = sqlx::query_as(&format!(
+            "SELECT token FROM {} WHERE id = $1 ORDER BY token DESC LIMIT 1",
+            self.table("lock")
+        ))
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve token".to_string()))?;
+        if current.map(|c| c.0) != Some(token as i64) {
+            return Err(PersistError::Fenced);
+        }
+        Ok(tx)
+    }
+
+    async fn lock_in(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        scope: LockScope,
+        lock_type: LockType,
+    ) -> Result<u64, PersistError> {
+        // lock rows are only ever appended, so lockers serialize on the saga row instead
+        sqlx::query(&format!(
+            "INSERT INTO {} (id) VALUES ($1) ON CONFLICT (id) DO UPDATE SET id = EXCLUDED.id",
+            self.table("")
+        ))
+        .bind(scope.id)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "lock saga".to_string()))?;
+        // every statement reads the latest commit once the saga row is locked
+        let (signalled,): (bool,) = sqlx::query_as(&format!(
+            "SELECT signalled FROM {} WHERE id = $1 FOR UPDATE",
+            self.table("")
+        ))
+        .bind(scope.id)
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve signalled".to_string()))?;
+        let row: Option<(Uuid, String, NaiveDateTime, i64, Option<NaiveDateTime>)> =
+            sqlx::query_as(&format!(
+                "SELECT executor_id, lock, dtc, token, wake_at FROM {}
+                    WHERE id = $1 ORDER BY token DESC LIMIT 1",
+                self.table("lock")
+            ))
+            .bind(scope.id)
+            .fetch_optional(&mut **tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "retrieve lock".to_string()))?;
+
+        if scope.token.is_some() && row.as_ref().map(|r| r.3 as u64) != scope.token {
+            return Err(PersistError::Fenced);
+        }
+        if row.as_ref().is_some_and(|r| r.1 == CANCELLED) {
+            return Err(PersistError::Cancelled);
+        }
+        let now = Utc::now().naive_utc();
+        let lockable = row.is_none_or(|(executor_id, lock, dtc, _, wake_at)| {
+            scope.executor_id == executor_id
+                || match lock.as_str() {
+                    FAILED | PAUSED => true,
+                    WAITING => signalled,
+                    SLEEPING => wake_at.is_some_and(|w| w <= now),
+                    _ => now > dtc + self.lock_timeout,
+                }
+        });
+        if !lockable {
+            return Err(PersistError::Locked);
+        }
+
+        if matches!(lock_type, LockType::Finished) {
+            for table in [
+                "lock",
+                "step",
+                "compensation",
+                "attempt",
+                "started",
+                "signal",
+                "",
+            ] {
+                sqlx::query(&format!("DELETE FROM {} WHERE id = $1", self.table(table)))
+                    .bind(scope.id)
+                    .execute(&mut **tx)
+                    .await
+                    .map_err(|e| {
+                        PersistError::Execution(e.to_string(), "finished saga".to_string())
+                    })?;
+            }
+            let (token,): (i64,) = sqlx::query_as(&format!(
+                "SELECT nextval('{}')",
+                self.table("lock_token_seq").replace('\'', "''")
+            ))
+            .fetch_one(&mut **tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "next token".to_string()))?;
+            return Ok(token as u64);
+        }
+
+        if !matches!(lock_type, LockType::Waiting) {
+            // signals arriving from now on must wake the saga up again once it waits
+            sqlx::query(&format!(
+                "UPDATE {} SET signalled = false WHERE id = $1",
+                self.table("")
+            ))
+            .bind(scope.id)
+            .execute(&mut **tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "reset signalled".to_string()))?;
+        }
+        if matches!(lock_type, LockType::Cancelled) {
+            // earlier locks must not make the cancelled saga look failed
+            sqlx::query(&format!("DELETE FROM {} WHERE id = $1", self.table("lock")))
+                .bind(scope.id)
+                .execute(&mut **tx)
+                .await
+                .map_err(|e| {
+                    PersistError::Execution(e.to_string(), "cancelled saga lock".to_string())
+                })?;
+        }
+        let wake_at = match lock_type {
+            LockType::Sleeping(wake_at) => Some(DateTime::<Utc>::from(wake_at).naive_utc()),
+            _ => None,
+        };
+        let (token,): (i64,) = sqlx::query_as(&format!(
+            "INSERT INTO {} (id, executor_id, name, lock, dtc, parent_id, wake_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                RETURNING token",
+            self.table("lock")
+        ))
+        .bind(scope.id)
+        .bind(scope.executor_id)
+        .bind(scope.name)
+        .bind(lock_name(&lock_type))
+        .bind(now)
+        .bind(scope.parent)
+        .bind(wake_at)
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "insert lock".to_string()))?;
+        Ok(token as u64)
+    }
+
+    async fn store_in(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        id: Uuid,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (id, step, state)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (id, step) DO UPDATE SET state = EXCLUDED.state",
+            self.table("step")
+        ))
+        .bind(id)
+        .bind(step.to_string())
+        .bind(state)
+        .execute(&mut **tx)
+        .await
+        .map(|_| ())
+        .map_err(|e| PersistError::Execution(e.to_string(), "store step".to_string()))
+    }
+
+    async fn store_version_in(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        id: Uuid,
+        version: u32,
+    ) -> Result<(), PersistError> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (id, version)
+                VALUES ($1, $2)
+                ON CONFLICT (id) DO UPDATE SET version = EXCLUDED.version",
+            self.table("")
+        ))
+        .bind(id)
+        .bind(version as i32)
+        .execute(&mut **tx)
+        .await
+        .map(|_| ())
+        .map_err(|e| PersistError::Execution(e.to_string(), "store version".to_string()))
+    }
+
+    // single fenced statement on the saga table or a table keyed by id and step
+    async fn write(
+        &self,
+        id: Uuid,
+        token: u64,
+        sql: String,
+        bind: impl FnOnce(
+            sqlx::query::Query<'_, Postgres, sqlx::postgres::PgArguments>,
+        ) -> sqlx::query::Query<'_, Postgres, sqlx::postgres::PgArguments>,
+        context: &str,
+    ) -> Result<(), PersistError> {
+        let mut tx = self.begin_fenced(id, token).await?;
+        bind(sqlx::query(&sql).bind(id))
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), context.to_string()))?;
+        commit(tx, context).await
+    }
+}
+
+#[async_trait::async_trait]
+impl StepPersister for PostgresPersister {
+    async fn lock(&self, scope: LockScope, lock_type: LockType) -> Result<u64, PersistError> {
+        let mut tx = self.begin("lock transaction").await?;
+        let token = self.lock_in(&mut tx, scope, lock_type).await?;
+        commit(tx, "lock commit").await?;
+        Ok(token)
+    }
+
+    async fn renew(&self, scope: LockScope) -> Result<(), PersistError> {
+        let mut tx = self.begin("renew transaction").await?;
+        let holder: Option<(Uuid, i64)> = sqlx::query_as(&format!(
+            "SELECT executor_id, token FROM {} WHERE id = $1 ORDER BY token DESC LIMIT 1 FOR UPDATE",
+            self.table("lock")
+        ))
+        .bind(scope.id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve lock".to_string()))?;
+        let held = holder.is_some_and(|(executor_id, token)| {
+            executor_id == scope.executor_id && scope.token.is_none_or(|t| t as i64 == token)
+        });
+        if !held {
+            return Err(PersistError::LockLost);
+        }
+
+        sqlx::query(&format!(
+            "UPDATE {} SET dtc = $3 WHERE id = $1 AND executor_id = $2",
+            self.table("lock")
+        ))
+        .bind(scope.id)
+        .bind(scope.executor_id)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "renew lock".to_string()))?;
+        commit(tx, "renew commit").await
+    }
+
+    async fn retrieve(&self, id: Uuid) -> Result<SagaState, PersistError> {
+        let steps = |table: &str| {
+            format!(
+                "SELECT step, state FROM {} WHERE id = $1",
+                self.table(table)
+            )
+        };
+        let rows: Vec<(String, String)> = sqlx::query_as(&steps("step"))
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "retrieve".to_string()))?;
+        let states = rows
+            .into_iter()
+            .map(|row| (StepKey::from(row.0), row.1))
+            .collect();
+        let rows: Vec<(String, String)> = sqlx::query_as(&steps("compensation"))
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| {
+                PersistError::Execution(e.to_string(), "retrieve compensation".to_string())
+            })?;
+        let compensations = rows
+            .into_iter()
+            .map(|row| (StepKey::from(row.0), row.1))
+            .collect();
+        let rows: Vec<(String, i32)> = sqlx::query_as(&format!(
+            "SELECT step, attempt FROM {} WHERE id = $1",
+            self.table("attempt")
+        ))
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve attempts".to_string()))?;
+        let attempts = rows
+            .into_iter()
+            .map(|row| (StepKey::from(row.0), row.1 as u32))
+            .collect();
+        let rows: Vec<(String,)> = sqlx::query_as(&format!(
+            "SELECT step FROM {} WHERE id = $1",
+            self.table("started")
+        ))
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve started".to_string()))?;
+        let started = rows.into_iter().map(|row| StepKey::from(row.0)).collect();
+        let signals: Vec<(String, String)> = sqlx::query_as(&format!(
+            "SELECT name, payload FROM {} WHERE id = $1",
+            self.table("signal")
+        ))
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve signals".to_string()))?;
+        let saga: Option<(bool, Option<NaiveDateTime>, Option<i32>, bool)> =
+            sqlx::query_as(&format!(
+                "SELECT cancelled, deadline, version, cancel_requested FROM {} WHERE id = $1",
+                self.table("")
+            ))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "retrieve saga".to_string()))?;
+        Ok(SagaState {
+            id,
+            states,
+            compensations,
+            attempts,
+            started,
+            deadline: saga
+                .and_then(|s| s.1)
+                .map(|d| SystemTime::from(d.and_utc())),
+            cancelled: saga.map(|s| s.0).unwrap_or_default(),
+            cancel_requested: saga.map(|s| s.3).unwrap_or_default(),
+            signals: signals.into_iter().collect(),
+            version: saga.and_then(|s| s.2).map(|v| v as u32),
+        })
+    }
+
+    async fn store(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError> {
+        let mut tx = self.begin_fenced(id, token).await?;
+        self.store_in(&mut tx, id, step, state).await?;
+        commit(tx, "store commit").await
+    }
+
+    async fn store_compensation(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError> {
+        let sql = format!(
+            "INSERT INTO {} (id, step, state)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (id, step) DO UPDATE SET state = EXCLUDED.state",
+            self.table("compensation")
+        );
+        self.write(
+            id,
+            token,
+            sql,
+            |q| q.bind(step.to_string()).bind(state),
+            "store compensation",
+        )
+        .await
+    }
+
+    async fn store_cancelled(&self, id: Uuid, token: u64) -> Result<(), PersistError> {
+        let sql = format!(
+            "INSERT INTO {} (id, cancelled)
+                VALUES ($1, true)
+                ON CONFLICT (id) DO UPDATE SET cancelled = true",
+            self.table("")
+        );
+        self.write(id, token, sql, |q| q, "store cancelled").await
+    }
+
+    async fn store_started(&self, id: Uuid, token: u64, step: StepKey) -> Result<(), PersistError> {
+        let sql = format!(
+            "INSERT INTO {} (id, step) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            self.table("started")
+        );
+        self.write(
+            id,
+            token,
+            sql,
+            |q| q.bind(step.to_string()),
+            "store started",
+        )
+        .await
+    }
+
+    async fn store_attempt(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        attempt: u32,
+    ) -> Result<(), PersistError> {
+        let sql = format!(
+            "INSERT INTO {} (id, step, attempt)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (id, step) DO UPDATE SET attempt = EXCLUDED.attempt",
+            self.table("attempt")
+        );
+        self.write(
+            id,
+            token,
+            sql,
+            |q| q.bind(step.to_string()).bind(attempt as i32),
+            "store attempt",
+        )
+        .await
+    }
+
+    async fn store_deadline(
+        &self,
+        id: Uuid,
+        token: u64,
+        deadline: SystemTime,
+    ) -> Result<(), PersistError> {
+        let sql = format!(
+            "INSERT INTO {} (id, deadline)
+                VALUES ($1, $2)
+                ON CONFLICT (id) DO UPDATE SET deadline = EXCLUDED.deadline",
+            self.table("")
+        );
+        self.write(
+            id,
+            token,
+            sql,
+            |q| q.bind(DateTime::<Utc>::from(deadline).naive_utc()),
+            "store deadline",
+        )
+        .await
+    }
+
+    async fn store_version(&self, id: Uuid, token: u64, version: u32) -> Result<(), PersistError> {
+        let mut tx = self.begin_fenced(id, token).await?;
+        self.store_version_in(&mut tx, id, version).await?;
+        commit(tx, "version commit").await
+    }
+
+    async fn store_migration(
+        &self,
+        id: Uuid,
+        token: u64,
+        version: u32,
+        states: BTreeMap<StepKey, String>,
+    ) -> Result<(), PersistError> {
+        let mut tx = self.begin_fenced(id, token).await?;
+        sqlx::query(&format!("DELETE FROM {} WHERE id = $1", self.table("step")))
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "migrate steps".to_string()))?;
+        for (step, state) in states {
+            self.store_in(&mut tx, id, step, state).await?;
+        }
+        self.store_version_in(&mut tx, id, version).await?;
+        commit(tx, "migration commit").await
+    }
+
+    async fn cancel(&self, id: Uuid) -> Result<(), PersistError> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (id, cancel_requested)
+                VALUES ($1, true)
+                ON CONFLICT (id) DO UPDATE SET cancel_requested = true",
+            self.table("")
+        ))
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| PersistError::Execution(e.to_string(), "cancel".to_string()))
+    }
+
+    async fn is_cancel_requested(&self, id: Uuid) -> Result<bool, PersistError> {
+        let saga: Option<(bool,)> = sqlx::query_as(&format!(
+            "SELECT cancel_requested FROM {} WHERE id = $1",
+            self.table("")
+        ))
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve cancel".to_string()))?;
+        Ok(saga.is_some_and(|s| s.0))
+    }
+
+    async fn pause(&self, selector: SagaSelector) -> Result<(), PersistError> {
+        let table = self.table("pause");
+        let result = match selector {
+            SagaSelector::Id(id) => {
+                sqlx::query(&format!(
+                    "INSERT INTO {table} (id) VALUES ($1) ON CONFLICT DO NOTHING"
+                ))
+                .bind(id)
+                .execute(&self.pool)
+                .await
+            }
+            SagaSelector::Name(name) => {
+                sqlx::query(&format!(
+                    "INSERT INTO {table} (name) VALUES ($1) ON CONFLICT DO NOTHING"
+                ))
+                .bind(name)
+                .execute(&self.pool)
+                .await
+            }
+        };
+        result
+            .map(|_| ())
+            .map_err(|e| PersistError::Execution(e.to_string(), "pause".to_string()))
+    }
+
+    async fn unpause(&self, selector: SagaSelector) -> Result<(), PersistError> {
+        let table = self.table("pause");
+        let result = match selector {
+            SagaSelector::Id(id) => {
+                sqlx::query(&format!("DELETE FROM {table} WHERE id = $1"))
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await
+            }
+            SagaSelector::Name(name) => {
+                sqlx::query(&format!("DELETE FROM {table} WHERE name = $1"))
+                    .bind(name)
+                    .execute(&self.pool)
+                    .await
+            }
+        };
+        result
+            .map(|_| ())
+            .map_err(|e| PersistError::Execution(e.to_string(), "unpause".to_string()))
+    }
+
+    async fn is_paused(&self, id: Uuid, name: &str) -> Result<bool, PersistError> {
+        let (paused,): (bool,) = sqlx::query_as(&format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE id = $1 OR name = $2)",
+            self.table("pause")
+        ))
+        .bind(id)
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve pause".to_string()))?;
+        Ok(paused)
+    }
+
+    async fn requested_interruptions(
+        &self,
+        id: Uuid,
+        name: &str,
+    ) -> Result<(bool, bool), PersistError> {
+        sqlx::query_as(&format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE id = $1 AND cancel_requested),
+                EXISTS (SELECT 1 FROM {} WHERE id = $1 OR name = $2)",
+            self.table(""),
+            self.table("pause")
+        ))
+        .bind(id)
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve interruptions".to_string()))
+    }
+
+    async fn signal(&self, id: Uuid, name: &str, payload: String) -> Result<(), PersistError> {
+        let mut tx = self.begin("signal transaction").await?;
+        sqlx::query(&format!(
+            "INSERT INTO {} (id, name, payload)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (id, name) DO UPDATE SET payload = EXCLUDED.payload",
+            self.table("signal")
+        ))
+        .bind(id)
+        .bind(name)
+        .bind(payload)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "store signal".to_string()))?;
+        // parents parked by the waiting child are woken up with it
+        let mut next = Some(id);
+        while let Some(saga) = next {
+            sqlx::query(&format!(
+                "INSERT INTO {} (id, signalled)
+                    VALUES ($1, true)
+                    ON CONFLICT (id) DO UPDATE SET signalled = true",
+                self.table("")
+            ))
+            .bind(saga)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "store signalled".to_string()))?;
+            next = sqlx::query_as::<_, (Uuid,)>(&format!(
+                "SELECT parent_id FROM {} WHERE id = $1 AND parent_id IS NOT NULL LIMIT 1",
+                self.table("lock")
+            ))
+            .bind(saga)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "retrieve parent".to_string()))?
+            .map(|p| p.0);
+        }
+        commit(tx, "signal commit").await
+    }
+
+    async fn store_consumed_signal(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+        name: &str,
+    ) -> Result<(), PersistError> {
+        let mut tx = self.begin_fenced(id, token).await?;
+        self.store_in(&mut tx, id, step, state).await?;
+        sqlx::query(&format!(
+            "DELETE FROM {} WHERE id = $1 AND name = $2",
+            self.table("signal")
+        ))
+        .bind(id)
+        .bind(name)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "consume signal".to_string()))?;
+        commit(tx, "signal step commit").await
+    }
+
+    async fn store_schedule(&self, schedule: Schedule) -> Result<(), PersistError> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (id, name, data, cron, next_at)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name, data = EXCLUDED.data,
+                    cron = EXCLUDED.cron, next_at = EXCLUDED.next_at",
+            self.table("schedule")
+        ))
+        .bind(schedule.id)
+        .bind(schedule.name)
+        .bind(schedule.data)
+        .bind(schedule.cron)
+        .bind(DateTime::<Utc>::from(schedule.next_at).naive_utc())
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| PersistError::Execution(e.to_string(), "store schedule".to_string()))
+    }
+
+    async fn remove_schedule(&self, id: Uuid) -> Result<(), PersistError> {
+        sqlx::query(&format!(
+            "DELETE FROM {} WHERE id = $1",
+            self.table("schedule")
+        ))
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| PersistError::Execution(e.to_string(), "remove schedule".to_string()))
+    }
+
+    async fn get_due_schedules(&self, now: SystemTime) -> Result<Vec<Schedule>, PersistError> {
+        let rows: Vec<(Uuid, String, String, Option<String>, NaiveDateTime)> = sqlx::query_as(
+            &format!(
+                "SELECT id, name, data, cron, next_at FROM {} WHERE next_at <= $1",
+                self.table("schedule")
+            ),
+        )
+        .bind(DateTime::<Utc>::from(now).naive_utc())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve schedules".to_string()))?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, name, data, cron, next_at)| Schedule {
+                id,
+                name,
+                data,
+                cron,
+                next_at: SystemTime::from(next_at.and_utc()),
+            })
+            .collect())
+    }
+
+    async fn start_occurrence(
+        &self,
+        schedule: Schedule,
+        next_at: Option<SystemTime>,
+    ) -> Result<Uuid, PersistError> {
+        let table = self.table("schedule");
+        // the schedule row stays locked until the occurrence is created, so it starts once
+        let mut tx = self.begin("schedule transaction").await?;
+        let from = DateTime::<Utc>::from(schedule.next_at).naive_utc();
+        let result = match next_at {
+            Some(next_at) => {
+                sqlx::query(&format!(
+                    "UPDATE {table} SET next_at = $3 WHERE id = $1 AND next_at = $2"
+                ))
+                .bind(schedule.id)
+                .bind(from)
+                .bind(DateTime::<Utc>::from(next_at).naive_utc())
+                .execute(&mut *tx)
+                .await
+            }
+            None => {
+                sqlx::query(&format!(
+                    "DELETE FROM {table} WHERE id = $1 AND next_at = $2"
+                ))
+                .bind(schedule.id)
+                .bind(from)
+                .execute(&mut *tx)
+                .await
+            }
+        }
+        .map_err(|e| PersistError::Execution(e.to_string(), "advance schedule".to_string()))?;
+        if result.rows_affected() == 0 {
+            return Err(PersistError::Locked);
+        }
+
+        let scope = LockScope::from_id(schedule.occurrence_id(schedule.next_at), schedule.name);
+        let token = self
+            .lock_in(&mut tx, scope.clone(), LockType::Initial)
+            .await?;
+        self.store_in(&mut tx, scope.id, StepKey::Index(0), schedule.data)
+            .await?;
+        self.lock_in(
+            &mut tx,
+            LockScope {
+                token: Some(token),
+                ..scope.clone()
+            },
+            LockType::Failed,
+        )
+        .await?;
+        commit(tx, "schedule commit").await?;
+        Ok(scope.id)
+    }
+
+    async fn get_next_failed(
+        &self,
+        for_duration: Duration,
+    ) -> Result<Option<(Uuid, String, Uuid)>, PersistError> {
+        let mut tx = self.begin("get transaction").await?;
+        let now = Utc::now().naive_utc();
+        // only the latest lock of every saga counts, a paused child keeps its parent paused
+        let result: Option<(Uuid, String)> = sqlx::query_as(&format!(
+            "SELECT l.id, l.name FROM (
+                    SELECT DISTINCT ON (id) id, name, lock, dtc, parent_id, wake_at FROM {lock}
+                        ORDER BY id, token DESC
+                ) l
+                LEFT JOIN {saga} s ON s.id = l.id
+                WHERE l.parent_id IS NULL
+                    AND (l.lock IN ($2, $5)
+                        OR (l.lock = $6 AND s.signalled)
+                        OR (l.lock = $7 AND l.wake_at <= $8)
+                        OR (l.dtc < $1 AND l.lock NOT IN ($3, $4, $6, $7)))
+                    AND NOT EXISTS (SELECT 1 FROM {pause} p WHERE p.id = l.id OR p.name = l.name)
+                    AND NOT EXISTS (SELECT 1 FROM {lock} c JOIN {pause} p ON p.id = c.id OR p.name = c.name
+                        WHERE c.parent_id = l.id)
+                ORDER BY l.dtc DESC LIMIT 1",
+            lock = self.table("lock"),
+            saga = self.table(""),
+            pause = self.table("pause"),
+        ))
+        .bind(now - for_duration)
+        .bind(FAILED)
+        .bind(FINISHED)
+        .bind(CANCELLED)
+        .bind(PAUSED)
+        .bind(WAITING)
+        .bind(SLEEPING)
+        .bind(now)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve failed".to_string()))?;
+
+        let Some((id, name)) = result else {
+            return Ok(None);
+        };
+        let executor_id = Uuid::new_v4();
+        let scope = LockScope {
+            id,
+            executor_id,
+            name,
+            parent: None,
+            token: None,
+        };
+        self.lock_in(&mut tx, scope.clone(), LockType::Retry)
+            .await?;
+        commit(tx, "get commit").await?;
+        Ok(Some((scope.id, scope.name, executor_id)))
+    }
+}
+
+#[async_trait::async_trait]
+impl TransactionalPersister for PostgresPersister {
+    type Transaction = Transaction<'static, Postgres>;
+
+    async fn begin(
+        &self,
+        id: Uuid,
+        token: u64,
+    ) -> Result<Transaction<'static, Postgres>, PersistError> {
+        self.begin_fenced(id, token).await
+    }
+
+    async fn commit_step(
+        &self,
+        mut transaction: Transaction<'static, Postgres>,
+        id: Uuid,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError> {
+        self.store_in(&mut transaction, id, step, state).await?;
+        commit(transaction, "step commit").await
+    }
+}
+
+const EXECUTING: &str = "Executing";
+const FAILED: &str = "Failed";
+const FINISHED: &str = "Finished";
+const INITIAL: &str = "Initial";
+const RETRY: &str = "Retry";
+const PAUSED: &str = "Paused";
+const WAITING: &str = "Waiting";
+const SLEEPING: &str = "Sleeping";
+const CANCELLED: &str = "Cancelled";
+
+fn lock_name(lock_type: &LockType) -> &'static str {
+    match lock_type {
+        LockType::Executing => EXECUTING,
+        LockType::Failed => FAILED,
+        LockType::Finished => FINISHED,
+        LockType::Initial => INITIAL,
+        LockType::Retry => RETRY,
+        LockType::Paused => PAUSED,
+        LockType::Waiting => WAITING,
+        LockType::Sleeping(_) => SLEEPING,
+        LockType::Cancelled => CANCELLED,
+    }
+}
+
+// identifiers are quoted, quotes within them are doubled
+fn escape(identifier: &str) -> String {
+    identifier.replace('"', "\"\"")
+}
+
+async fn commit(tx: Transaction<'_, Postgres>, context: &str) -> Result<(), PersistError> {
+    tx.commit()
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), context.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use sqlx::postgres::PgPoolOptions;
+
+    use crate::definitions::saga_definition::{SagaDefinition, SagaRunner};
+
+    use super::*;
+
+    // needs a server, the tests are skipped unless TEST_POSTGRES_URL is set
+    async fn persister(lock_timeout: Duration) -> Option<PostgresPersister> {
+        let url = std::env::var("TEST_POSTGRES_URL").ok()?;
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect_lazy(&url)
+            .unwrap();
+        let persister = PostgresPersister::new(pool, lock_timeout)
+            .schema("transaction_state_test")
+            .table_prefix("saga");
+        persister.migrate().await.unwrap();
+        Some(persister)
+    }
+
+    #[tokio::test]
+    async fn test_tables_are_quoted() {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/test")
+            .unwrap();
+        let persister = PostgresPersister::new(pool, Duration::from_secs(1))
+            .schema("my\"schema")
+            .table_prefix("order_saga");
+        assert_eq!("\"my\"\"schema\".\"order_saga\"", persister.table(""));
+        assert_eq!(
+            "\"my\"\"schema\".\"order_saga_lock\"",
+            persister.table("lock")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_same_executor_can_always_lock() {
+        let Some(persister) = persister(Duration::from_millis(10)).await else {
+            return;
+        };
+        let scope = LockScope::from_id(Uuid::new_v4(), "test1".to_string());
+        for lock_type in [
+            LockType::Initial,
+            LockType::Failed,
+            LockType::Retry,
+            LockType::Executing,
+            LockType::Finished,
+        ] {
+            persister.lock(scope.clone(), lock_type).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_different_executor_can_lock_conditionally() {
+        let Some(persister) = persister(Duration::from_millis(100)).await else {
+            return;
+        };
+        let scope1 = LockScope::from_id(Uuid::new_v4(), "test1".to_string());
+        let scope2 = LockScope::from_id(scope1.id, "test1".to_string());
+        persister
+            .lock(scope1.clone(), LockType::Initial)
+            .await
+            .unwrap();
+
+        let result = persister.lock(scope2.clone(), LockType::Executing).await;
+        assert!(matches!(result, Err(PersistError::Locked)), "{result:?}");
+
+        sleep(Duration::from_millis(113));
+
+        let result = persister.lock(scope2.clone(), LockType::Failed).await;
+        assert!(result.is_ok(), "{result:?}");
+
+        let result = persister.lock(scope1.clone(), LockType::Executing).await;
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_executors_lock_once() {
+        let Some(persister) = persister(Duration::from_secs(5)).await else {
+            return;
+        };
+        let id = Uuid::new_v4();
+        let locks = (0..5).map(|_| {
+            let persister = persister.clone();
+            async move {
+                persister
+                    .lock(
+                        LockScope::from_id(id, "test1".to_string()),
+                        LockType::Initial,
+                    )
+                    .await
+            }
+        });
+        let results = futures_util::future::join_all(locks).await;
+        assert_eq!(
+            1,
+            results.iter().filter(|r| r.is_ok()).count(),
+            "{results:?}"
+        );
+        assert!(results
+            .iter()
+            .all(|r| matches!(r, Ok(_) | Err(PersistError::Locked))));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_tables_are_converted() {
+        let Some(persister) = persister(Duration::from_secs(5)).await else {
+            return;
+        };
+        let schema = format!("legacy_{}", Uuid::new_v4().simple());
+        let legacy = PostgresPersister::new(persister.pool.clone(), Duration::from_secs(5))
+            .schema(schema.clone());
+        let id = Uuid::new_v4();
+        // schema of the order-ticket example before the library shipped its persister
+        persister
+            .pool
+            .execute(
+                format!(
+                    "CREATE SCHEMA {schema};
+                    CREATE TYPE {schema}.lock_type AS ENUM
+                        ('Executing', 'Failed', 'Finished', 'Initial', 'Retry');
+                    CREATE TABLE {schema}.saga_step (
+                        id uuid NOT NULL,
+                        step smallint NOT NULL,
+                        state text NOT NULL,
+                        dtc TIMESTAMP NOT NULL DEFAULT NOW ()
+                    );
+                    CREATE TABLE {schema}.saga_lock (
+                        id uuid NOT NULL,
+                        executor_id uuid NOT NULL,
+                        name varchar NOT NULL,
+                        lock {schema}.lock_type NOT NULL,
+                        dtc TIMESTAMP NOT NULL DEFAULT NOW ()
+                    );
+                    CREATE UNIQUE INDEX saga_step_id_step_idx ON {schema}.saga_step (id, step);
+                    INSERT INTO {schema}.saga_step (id, step, state) VALUES ('{id}', 0, '7');
+                    INSERT INTO {schema}.saga_lock (id, executor_id, name, lock, dtc) VALUES
+                        ('{id}', '{id}', 'legacy', 'Failed', NOW () - INTERVAL '1 minute'),
+                        ('{id}', '{id}', 'legacy', 'Initial', NOW () - INTERVAL '2 minutes');"
+                )
+                .as_str(),
+            )
+            .await
+            .unwrap();
+
+        legacy.migrate().await.unwrap();
+        legacy.migrate().await.unwrap();
+
+        let saga = legacy.retrieve(id).await.unwrap();
+        assert_eq!(Some(&"7".to_string()), saga.states.get(&StepKey::Index(0)));
+        // the latest legacy lock is the failed one
+        let next = legacy.get_next_failed(Duration::ZERO).await.unwrap();
+        assert_eq!(Some((id, "legacy".to_string())), next.map(|n| (n.0, n.1)));
+        persister
+            .pool
+            .execute(format!("DROP SCHEMA {schema} CASCADE").as_str())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_initial_state_commits_with_the_transaction() {
+        let Some(persister) = persister(Duration::from_secs(5)).await else {
+            return;
+        };
+        let scope = LockScope::from_id(Uuid::new_v4(), "test1".to_string());
+        let mut tx = persister.pool.begin().await.unwrap();
+        let token = persister
+            .save_initial_state(&mut tx, scope.clone(), &7)
+            .await
+            .unwrap();
+        tx.rollback().await.unwrap();
+        assert!(matches!(
+            persister.begin_fenced(scope.id, token).await,
+            Err(PersistError::Fenced)
+        ));
+
+        let mut tx = persister.pool.begin().await.unwrap();
+        persister
+            .save_initial_state(&mut tx, scope.clone(), &7)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        let saga = persister.retrieve(scope.id).await.unwrap();
+        assert_eq!(Some(&"7".to_string()), saga.states.get(&StepKey::Index(0)));
+    }
+
+    fn create_definition(
+        lock_scope: LockScope,
+        fail: bool,
+        persister: PostgresPersister,
+    ) -> SagaDefinition<u32, u32, u32, PersistError, PostgresPersister> {
+        SagaDefinition::new(lock_scope, |v: u32, _: &()| v, (), persister)
+            .transactional_step(
+                |v: u32, tx| async move { Ok::<_, PersistError>((v + 1, tx)) },
+                |v, _| *v,
+            )
+            .step(
+                move |v: u32| async move {
+                    if fail {
+                        Err(PersistError::Timeout)
+                    } else {
+                        Ok(v * 2)
+                    }
+                },
+                |_, v| v,
+            )
+    }
+
+    #[tokio::test]
+    async fn test_failed_saga_is_resumed() {
+        let Some(persister) = persister(Duration::from_secs(5)).await else {
+            return;
+        };
+        let persister = persister.table_prefix("resumed_saga");
+        persister.migrate().await.unwrap();
+        sqlx::query(&format!("DELETE FROM {}", persister.table("lock")))
+            .execute(&persister.pool)
+            .await
+            .unwrap();
+        let scope = LockScope::from_id(Uuid::new_v4(), "resumed".to_string());
+        let result = create_definition(scope.clone(), true, persister.clone())
+            .run(7)
+            .await;
+        assert!(matches!(result, Err(PersistError::Timeout)), "{result:?}");
+        let saga = persister.retrieve(scope.id).await.unwrap();
+        assert_eq!(Some(&"8".to_string()), saga.states.get(&StepKey::Index(1)));
+
+        let (id, name, executor_id) = persister
+            .get_next_failed(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((scope.id, "resumed".to_string()), (id, name.clone()));
+        let scope = LockScope {
+            executor_id,
+            ..LockScope::from_id(id, name)
+        };
+        let result = create_definition(scope, false, persister.clone())
+            .continue_from_last_step()
+            .await;
+        assert_eq!(16, result.unwrap());
+        assert!(matches!(
+            persister.retrieve(id).await.map(|s| s.states.is_empty()),
+            Ok(true)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_signalled_child_wakes_parent() {
+        let Some(persister) = persister(Duration::from_secs(5)).await else {
+            return;
+        };
+        let persister = persister.table_prefix("parent_saga");
+        persister.migrate().await.unwrap();
+        sqlx::query(&format!("DELETE FROM {}", persister.table("lock")))
+            .execute(&persister.pool)
+            .await
+            .unwrap();
+        let parent = LockScope::from_id(Uuid::new_v4(), "parent".to_string());
+        let child = parent.child(&StepKey::Index(1));
+        persister
+            .lock(child.clone(), LockType::Waiting)
+            .await
+            .unwrap();
+        persister
+            .lock(parent.clone(), LockType::Waiting)
+            .await
+            .unwrap();
+        persister.pause(SagaSelector::Id(child.id)).await.unwrap();
+        persister
+            .signal(child.id, "approval", "true".to_string())
+            .await
+            .unwrap();
+        // a paused child keeps its parent paused
+        assert!(persister
+            .get_next_failed(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .is_none());
+
+        persister.unpause(SagaSelector::Id(child.id)).await.unwrap();
+        let next = persister
+            .get_next_failed(Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(Some(parent.id), next.map(|(id, _, _)| id));
+    }
+}
@@ -0,0 +1,79 @@
+CREATE TABLE IF NOT EXISTS `{prefix}` (
+    id BINARY(16) NOT NULL PRIMARY KEY,
+    cancelled BOOLEAN NOT NULL DEFAULT false,
+    cancel_requested BOOLEAN NOT NULL DEFAULT false,
+    signalled BOOLEAN NOT NULL DEFAULT false,
+    deadline BIGINT NULL,
+    version INT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS `{prefix}_step` (
+    id BINARY(16) NOT NULL,
+    step VARCHAR(255) NOT NULL,
+    state LONGTEXT NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, step)
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS `{prefix}_compensation` (
+    id BINARY(16) NOT NULL,
+    step VARCHAR(255) NOT NULL,
+    state LONGTEXT NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, step)
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS `{prefix}_attempt` (
+    id BINARY(16) NOT NULL,
+    step VARCHAR(255) NOT NULL,
+    attempt INT NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, step)
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS `{prefix}_started` (
+    id BINARY(16) NOT NULL,
+    step VARCHAR(255) NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, step)
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS `{prefix}_lock` (
+    token BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    id BINARY(16) NOT NULL,
+    executor_id BINARY(16) NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    `lock` VARCHAR(32) NOT NULL,
+    parent_id BINARY(16) NULL,
+    wake_at BIGINT NULL,
+    dtc BIGINT NOT NULL,
+    INDEX `{prefix}_lock_id_token_idx` (id, token),
+    INDEX `{prefix}_lock_dtc_idx` (dtc)
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS `{prefix}_pause` (
+    id BINARY(16) NULL,
+    name VARCHAR(255) NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE INDEX `{prefix}_pause_id_idx` (id),
+    UNIQUE INDEX `{prefix}_pause_name_idx` (name)
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS `{prefix}_signal` (
+    id BINARY(16) NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    payload LONGTEXT NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, name)
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS `{prefix}_schedule` (
+    id BINARY(16) NOT NULL PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    data LONGTEXT NOT NULL,
+    cron VARCHAR(255) NULL,
+    next_at BIGINT NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    INDEX `{prefix}_schedule_next_at_idx` (next_at)
+) ENGINE = InnoDB;
@@ -0,0 +1,83 @@
+CREATE SCHEMA IF NOT EXISTS "{schema}";
+
+CREATE TABLE IF NOT EXISTS "{schema}"."{prefix}" (
+    id uuid PRIMARY KEY,
+    cancelled boolean NOT NULL DEFAULT false,
+    cancel_requested boolean NOT NULL DEFAULT false,
+    signalled boolean NOT NULL DEFAULT false,
+    deadline TIMESTAMP NULL,
+    version integer NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT NOW ()
+);
+
+CREATE TABLE IF NOT EXISTS "{schema}"."{prefix}_step" (
+    id uuid NOT NULL,
+    step varchar NOT NULL,
+    state text NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT NOW ()
+);
+CREATE UNIQUE INDEX IF NOT EXISTS "{prefix}_step_id_step_idx" ON "{schema}"."{prefix}_step" (id, step);
+
+CREATE TABLE IF NOT EXISTS "{schema}"."{prefix}_compensation" (
+    id uuid NOT NULL,
+    step varchar NOT NULL,
+    state text NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT NOW ()
+);
+CREATE UNIQUE INDEX IF NOT EXISTS "{prefix}_compensation_id_step_idx" ON "{schema}"."{prefix}_compensation" (id, step);
+
+CREATE TABLE IF NOT EXISTS "{schema}"."{prefix}_attempt" (
+    id uuid NOT NULL,
+    step varchar NOT NULL,
+    attempt integer NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT NOW ()
+);
+CREATE UNIQUE INDEX IF NOT EXISTS "{prefix}_attempt_id_step_idx" ON "{schema}"."{prefix}_attempt" (id, step);
+
+CREATE TABLE IF NOT EXISTS "{schema}"."{prefix}_started" (
+    id uuid NOT NULL,
+    step varchar NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT NOW ()
+);
+CREATE UNIQUE INDEX IF NOT EXISTS "{prefix}_started_id_step_idx" ON "{schema}"."{prefix}_started" (id, step);
+
+CREATE SEQUENCE IF NOT EXISTS "{schema}"."{prefix}_lock_token_seq";
+CREATE TABLE IF NOT EXISTS "{schema}"."{prefix}_lock" (
+    id uuid NOT NULL,
+    executor_id uuid NOT NULL,
+    name varchar NOT NULL,
+    lock varchar NOT NULL,
+    parent_id uuid NULL,
+    wake_at TIMESTAMP NULL,
+    token bigint NOT NULL DEFAULT nextval('"{schema}"."{prefix}_lock_token_seq"'),
+    dtc TIMESTAMP NOT NULL DEFAULT NOW ()
+);
+CREATE INDEX IF NOT EXISTS "{prefix}_lock_id_token_idx" ON "{schema}"."{prefix}_lock" (id, token);
+CREATE INDEX IF NOT EXISTS "{prefix}_lock_lock_idx" ON "{schema}"."{prefix}_lock" (lock);
+CREATE INDEX IF NOT EXISTS "{prefix}_lock_dtc_idx" ON "{schema}"."{prefix}_lock" (dtc);
+
+CREATE TABLE IF NOT EXISTS "{schema}"."{prefix}_pause" (
+    id uuid NULL,
+    name varchar NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT NOW ()
+);
+CREATE UNIQUE INDEX IF NOT EXISTS "{prefix}_pause_id_idx" ON "{schema}"."{prefix}_pause" (id);
+CREATE UNIQUE INDEX IF NOT EXISTS "{prefix}_pause_name_idx" ON "{schema}"."{prefix}_pause" (name);
+
+CREATE TABLE IF NOT EXISTS "{schema}"."{prefix}_signal" (
+    id uuid NOT NULL,
+    name varchar NOT NULL,
+    payload text NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT NOW ()
+);
+CREATE UNIQUE INDEX IF NOT EXISTS "{prefix}_signal_id_name_idx" ON "{schema}"."{prefix}_signal" (id, name);
+
+CREATE TABLE IF NOT EXISTS "{schema}"."{prefix}_schedule" (
+    id uuid NOT NULL PRIMARY KEY,
+    name varchar NOT NULL,
+    data text NOT NULL,
+    cron varchar NULL,
+    next_at TIMESTAMP NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT NOW ()
+);
+CREATE INDEX IF NOT EXISTS "{prefix}_schedule_next_at_idx" ON "{schema}"."{prefix}_schedule" (next_at);
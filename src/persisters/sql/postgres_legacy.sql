@@ -0,0 +1,17 @@
+-- converts the lock and step tables of the persister the order-ticket example used to ship,
+-- the lock types become text and existing locks get tokens in the order they were taken
+CREATE SEQUENCE IF NOT EXISTS "{schema}"."{prefix}_lock_token_seq";
+ALTER TABLE "{schema}"."{prefix}_lock"
+    ALTER COLUMN lock TYPE varchar USING lock::varchar,
+    ADD COLUMN IF NOT EXISTS parent_id uuid NULL,
+    ADD COLUMN IF NOT EXISTS wake_at TIMESTAMP NULL,
+    ADD COLUMN IF NOT EXISTS token bigint NULL;
+UPDATE "{schema}"."{prefix}_lock" l SET token = ordered.token
+    FROM (SELECT ctid, row_number() OVER (ORDER BY dtc) AS token FROM "{schema}"."{prefix}_lock") ordered
+    WHERE l.ctid = ordered.ctid;
+SELECT setval('"{schema}"."{prefix}_lock_token_seq"', COALESCE(MAX(token), 0) + 1, false)
+    FROM "{schema}"."{prefix}_lock";
+ALTER TABLE "{schema}"."{prefix}_lock"
+    ALTER COLUMN token SET DEFAULT nextval('"{schema}"."{prefix}_lock_token_seq"'),
+    ALTER COLUMN token SET NOT NULL;
+ALTER TABLE "{schema}"."{prefix}_step" ALTER COLUMN step TYPE varchar USING step::varchar;
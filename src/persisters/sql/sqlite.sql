@@ -0,0 +1,85 @@
+CREATE TABLE IF NOT EXISTS "{prefix}" (
+    id BLOB PRIMARY KEY,
+    cancelled BOOLEAN NOT NULL DEFAULT false,
+    cancel_requested BOOLEAN NOT NULL DEFAULT false,
+    signalled BOOLEAN NOT NULL DEFAULT false,
+    deadline INTEGER NULL,
+    version INTEGER NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS "{prefix}_step" (
+    id BLOB NOT NULL,
+    step TEXT NOT NULL,
+    state TEXT NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, step)
+);
+
+CREATE TABLE IF NOT EXISTS "{prefix}_compensation" (
+    id BLOB NOT NULL,
+    step TEXT NOT NULL,
+    state TEXT NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, step)
+);
+
+CREATE TABLE IF NOT EXISTS "{prefix}_attempt" (
+    id BLOB NOT NULL,
+    step TEXT NOT NULL,
+    attempt INTEGER NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, step)
+);
+
+CREATE TABLE IF NOT EXISTS "{prefix}_started" (
+    id BLOB NOT NULL,
+    step TEXT NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, step)
+);
+
+-- single row counter, bumped first by every lock so lock transactions take the write lock up front
+CREATE TABLE IF NOT EXISTS "{prefix}_lock_token" (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    token INTEGER NOT NULL
+);
+INSERT OR IGNORE INTO "{prefix}_lock_token" (id, token) VALUES (0, 0);
+
+-- dtc and wake_at are milliseconds since the unix epoch
+CREATE TABLE IF NOT EXISTS "{prefix}_lock" (
+    id BLOB NOT NULL,
+    executor_id BLOB NOT NULL,
+    name TEXT NOT NULL,
+    lock TEXT NOT NULL,
+    parent_id BLOB NULL,
+    wake_at INTEGER NULL,
+    token INTEGER NOT NULL,
+    dtc INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "{prefix}_lock_id_token_idx" ON "{prefix}_lock" (id, token);
+CREATE INDEX IF NOT EXISTS "{prefix}_lock_dtc_idx" ON "{prefix}_lock" (dtc);
+
+CREATE TABLE IF NOT EXISTS "{prefix}_pause" (
+    id BLOB NULL UNIQUE,
+    name TEXT NULL UNIQUE,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS "{prefix}_signal" (
+    id BLOB NOT NULL,
+    name TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, name)
+);
+
+CREATE TABLE IF NOT EXISTS "{prefix}_schedule" (
+    id BLOB NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL,
+    data TEXT NOT NULL,
+    cron TEXT NULL,
+    next_at INTEGER NOT NULL,
+    dtc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS "{prefix}_schedule_next_at_idx" ON "{prefix}_schedule" (next_at);
@@ -0,0 +1,1210 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use sqlx::{sqlite::SqliteArguments, Executor, Pool, Sqlite, Transaction};
+use uuid::Uuid;
+
+use crate::definitions::{saga_state::SagaState, schedule::Schedule, step_key::StepKey};
+
+use super::persister::{
+    LockScope, LockType, PersistError, SagaSelector, StepPersister, TransactionalPersister,
+};
+
+const MIGRATION: &str = include_str!("sql/sqlite.sql");
+
+/// Persists sagas in SQLite tables named by the prefix, `saga` by default.
+///
+/// SQLite has a single writer, so locks are as strict as the ones of `InMemoryPersister`
+/// even with several pools on the same database file
+#[derive(Debug, Clone)]
+pub struct SqlitePersister {
+    pool: Pool<Sqlite>,
+    lock_timeout: Duration,
+    prefix: String,
+}
+
+impl SqlitePersister {
+    pub fn new(pool: Pool<Sqlite>, lock_timeout: Duration) -> Self {
+        Self {
+            pool,
+            lock_timeout,
+            prefix: "saga".to_string(),
+        }
+    }
+
+    /// Tables are named `{prefix}`, `{prefix}_step`, `{prefix}_lock` and so on
+    pub fn table_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Creates missing tables, safe to run on every start
+    pub async fn migrate(&self) -> Result<(), PersistError> {
+        let sql = MIGRATION.replace("{prefix}", &escape(&self.prefix));
+        let mut tx = self.begin("migration transaction").await?;
+        (&mut *tx)
+            .execute(sql.as_str())
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "migration".to_string()))?;
+        commit(tx, "migration commit").await
+    }
+
+    /// Locks the saga and stores its initial data in the transaction of the application writes,
+    /// so the saga exists once they commit. Resume it with `continue_from_last_step`.
+    ///
+    /// Call it before reading in the transaction, a transaction which read first fails with
+    /// `SQLITE_BUSY` once another connection wrote meanwhile
+    pub async fn save_initial_state<S: Serialize + Send + Sync>(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        scope: LockScope,
+        initial_state: &S,
+    ) -> Result<u64, PersistError> {
+        let state = serde_json::to_string(initial_state)?;
+        let token = self.lock_in(tx, scope.clone(), LockType::Initial).await?;
+        self.store_in(tx, scope.id, StepKey::Index(0), state)
+            .await?;
+        Ok(token)
+    }
+
+    fn table(&self, name: &str) -> String {
+        let name = match name {
+            "" => self.prefix.clone(),
+            name => format!("{}_{name}", self.prefix),
+        };
+        format!("\"{}\"", escape(&name))
+    }
+
+    async fn begin(&self, context: &str) -> Result<Transaction<'static, Sqlite>, PersistError> {
+        self.pool
+            .begin()
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), context.to_string()))
+    }
+
+    // transactions are deferred, one reading before its first write can't wait for the writer
+    // lock but fails right away, so the writer lock is taken up front
+    async fn begin_write(
+        &self,
+        context: &str,
+    ) -> Result<Transaction<'static, Sqlite>, PersistError> {
+        let mut tx = self.begin(context).await?;
+        sqlx::query(&format!(
+            "UPDATE {} SET token = token",
+            self.table("lock_token")
+        ))
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), context.to_string()))?;
+        Ok(tx)
+    }
+
+    // nobody else writes until commit, so no other executor can take over meanwhile
+    async fn begin_fenced(
+        &self,
+        id: Uuid,
+        token: u64,
+    ) -> Result<Transaction<'static, Sqlite>, PersistError> {
+        let mut tx = self.begin_write("fenced transaction").await?;
+        let current: Option<(i64,)> = sqlx::query_as(&format!(
+            "SELECT token FROM {} WHERE id = ? ORDER BY token DESC LIMIT 1",
+            self.table("lock")
+        ))
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve token".to_string()))?;
+        if current.map(|c| c.0) != Some(token as i64) {
+            return Err(PersistError::Fenced);
+        }
+        Ok(tx)
+    }
+
+    async fn lock_in(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        scope: LockScope,
+        lock_type: LockType,
+    ) -> Result<u64, PersistError> {
+        // writing first holds the writer lock for the rest of the transaction
+        let (token,): (i64,) = sqlx::query_as(&format!(
+            "UPDATE {} SET token = token + 1 RETURNING token",
+            self.table("lock_token")
+        ))
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "next token".to_string()))?;
+
+        let row: Option<(Uuid, String, i64, i64, Option<i64>)> = sqlx::query_as(&format!(
+            "SELECT executor_id, lock, dtc, token, wake_at FROM {}
+                WHERE id = ? ORDER BY token DESC LIMIT 1",
+            self.table("lock")
+        ))
+        .bind(scope.id)
+        .fetch_optional(&mut **tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve lock".to_string()))?;
+
+        if scope.token.is_some() && row.as_ref().map(|r| r.3 as u64) != scope.token {
+            return Err(PersistError::Fenced);
+        }
+        if row.as_ref().is_some_and(|r| r.1 == CANCELLED) {
+            return Err(PersistError::Cancelled);
+        }
+        let saga: Option<(bool,)> = sqlx::query_as(&format!(
+            "SELECT signalled FROM {} WHERE id = ?",
+            self.table("")
+        ))
+        .bind(scope.id)
+        .fetch_optional(&mut **tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve signalled".to_string()))?;
+        let signalled = saga.is_some_and(|s| s.0);
+        let now = millis(SystemTime::now());
+        let lockable = row.is_none_or(|(executor_id, lock, dtc, _, wake_at)| {
+            scope.executor_id == executor_id
+                || match lock.as_str() {
+                    FAILED | PAUSED => true,
+                    WAITING => signalled,
+                    SLEEPING => wake_at.is_some_and(|w| w <= now),
+                    _ => now > dtc + self.lock_timeout.as_millis() as i64,
+                }
+        });
+        if !lockable {
+            return Err(PersistError::Locked);
+        }
+
+        if matches!(lock_type, LockType::Finished) {
+            for table in [
+                "lock",
+                "step",
+                "compensation",
+                "attempt",
+                "started",
+                "signal",
+                "",
+            ] {
+                sqlx::query(&format!("DELETE FROM {} WHERE id = ?", self.table(table)))
+                    .bind(scope.id)
+                    .execute(&mut **tx)
+                    .await
+                    .map_err(|e| {
+                        PersistError::Execution(e.to_string(), "finished saga".to_string())
+                    })?;
+            }
+            return Ok(token as u64);
+        }
+
+        if !matches!(lock_type, LockType::Waiting) {
+            // signals arriving from now on must wake the saga up again once it waits
+            sqlx::query(&format!(
+                "UPDATE {} SET signalled = false WHERE id = ?",
+                self.table("")
+            ))
+            .bind(scope.id)
+            .execute(&mut **tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "reset signalled".to_string()))?;
+        }
+        if matches!(lock_type, LockType::Cancelled) {
+            // earlier locks must not make the cancelled saga look failed
+            sqlx::query(&format!("DELETE FROM {} WHERE id = ?", self.table("lock")))
+                .bind(scope.id)
+                .execute(&mut **tx)
+                .await
+                .map_err(|e| {
+                    PersistError::Execution(e.to_string(), "cancelled saga lock".to_string())
+                })?;
+        }
+        let wake_at = match lock_type {
+            LockType::Sleeping(wake_at) => Some(millis(wake_at)),
+            _ => None,
+        };
+        sqlx::query(&format!(
+            "INSERT INTO {} (id, executor_id, name, lock, dtc, parent_id, wake_at, token)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            self.table("lock")
+        ))
+        .bind(scope.id)
+        .bind(scope.executor_id)
+        .bind(scope.name)
+        .bind(lock_name(&lock_type))
+        .bind(now)
+        .bind(scope.parent)
+        .bind(wake_at)
+        .bind(token)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "insert lock".to_string()))?;
+        Ok(token as u64)
+    }
+
+    async fn store_in(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        id: Uuid,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (id, step, state)
+                VALUES (?, ?, ?)
+                ON CONFLICT (id, step) DO UPDATE SET state = excluded.state",
+            self.table("step")
+        ))
+        .bind(id)
+        .bind(step.to_string())
+        .bind(state)
+        .execute(&mut **tx)
+        .await
+        .map(|_| ())
+        .map_err(|e| PersistError::Execution(e.to_string(), "store step".to_string()))
+    }
+
+    async fn store_version_in(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        id: Uuid,
+        version: u32,
+    ) -> Result<(), PersistError> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (id, version)
+                VALUES (?, ?)
+                ON CONFLICT (id) DO UPDATE SET version = excluded.version",
+            self.table("")
+        ))
+        .bind(id)
+        .bind(version as i64)
+        .execute(&mut **tx)
+        .await
+        .map(|_| ())
+        .map_err(|e| PersistError::Execution(e.to_string(), "store version".to_string()))
+    }
+
+    // single fenced statement on the saga table or a table keyed by id and step
+    async fn write(
+        &self,
+        id: Uuid,
+        token: u64,
+        sql: String,
+        bind: impl for<'q> FnOnce(
+            sqlx::query::Query<'q, Sqlite, SqliteArguments<'q>>,
+        ) -> sqlx::query::Query<'q, Sqlite, SqliteArguments<'q>>,
+        context: &str,
+    ) -> Result<(), PersistError> {
+        let mut tx = self.begin_fenced(id, token).await?;
+        bind(sqlx::query(&sql).bind(id))
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), context.to_string()))?;
+        commit(tx, context).await
+    }
+}
+
+#[async_trait::async_trait]
+impl StepPersister for SqlitePersister {
+    async fn lock(&self, scope: LockScope, lock_type: LockType) -> Result<u64, PersistError> {
+        let mut tx = self.begin("lock transaction").await?;
+        let token = self.lock_in(&mut tx, scope, lock_type).await?;
+        commit(tx, "lock commit").await?;
+        Ok(token)
+    }
+
+    async fn renew(&self, scope: LockScope) -> Result<(), PersistError> {
+        let mut tx = self.begin_write("renew transaction").await?;
+        let holder: Option<(Uuid, i64)> = sqlx::query_as(&format!(
+            "SELECT executor_id, token FROM {} WHERE id = ? ORDER BY token DESC LIMIT 1",
+            self.table("lock")
+        ))
+        .bind(scope.id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve lock".to_string()))?;
+        let held = holder.is_some_and(|(executor_id, token)| {
+            executor_id == scope.executor_id && scope.token.is_none_or(|t| t as i64 == token)
+        });
+        if !held {
+            return Err(PersistError::LockLost);
+        }
+
+        sqlx::query(&format!(
+            "UPDATE {} SET dtc = ? WHERE id = ? AND executor_id = ?",
+            self.table("lock")
+        ))
+        .bind(millis(SystemTime::now()))
+        .bind(scope.id)
+        .bind(scope.executor_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "renew lock".to_string()))?;
+        commit(tx, "renew commit").await
+    }
+
+    async fn retrieve(&self, id: Uuid) -> Result<SagaState, PersistError> {
+        let steps =
+            |table: &str| format!("SELECT step, state FROM {} WHERE id = ?", self.table(table));
+        let rows: Vec<(String, String)> = sqlx::query_as(&steps("step"))
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "retrieve".to_string()))?;
+        let states = rows
+            .into_iter()
+            .map(|row| (StepKey::from(row.0), row.1))
+            .collect();
+        let rows: Vec<(String, String)> = sqlx::query_as(&steps("compensation"))
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| {
+                PersistError::Execution(e.to_string(), "retrieve compensation".to_string())
+            })?;
+        let compensations = rows
+            .into_iter()
+            .map(|row| (StepKey::from(row.0), row.1))
+            .collect();
+        let rows: Vec<(String, i64)> = sqlx::query_as(&format!(
+            "SELECT step, attempt FROM {} WHERE id = ?",
+            self.table("attempt")
+        ))
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve attempts".to_string()))?;
+        let attempts = rows
+            .into_iter()
+            .map(|row| (StepKey::from(row.0), row.1 as u32))
+            .collect();
+        let rows: Vec<(String,)> = sqlx::query_as(&format!(
+            "SELECT step FROM {} WHERE id = ?",
+            self.table("started")
+        ))
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve started".to_string()))?;
+        let started = rows.into_iter().map(|row| StepKey::from(row.0)).collect();
+        let signals: Vec<(String, String)> = sqlx::query_as(&format!(
+            "SELECT name, payload FROM {} WHERE id = ?",
+            self.table("signal")
+        ))
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve signals".to_string()))?;
+        let saga: Option<(bool, Option<i64>, Option<i64>, bool)> = sqlx::query_as(&format!(
+            "SELECT cancelled, deadline, version, cancel_requested FROM {} WHERE id = ?",
+            self.table("")
+        ))
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve saga".to_string()))?;
+        Ok(SagaState {
+            id,
+            states,
+            compensations,
+            attempts,
+            started,
+            deadline: saga.and_then(|s| s.1).map(from_millis),
+            cancelled: saga.map(|s| s.0).unwrap_or_default(),
+            cancel_requested: saga.map(|s| s.3).unwrap_or_default(),
+            signals: signals.into_iter().collect(),
+            version: saga.and_then(|s| s.2).map(|v| v as u32),
+        })
+    }
+
+    async fn store(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError> {
+        let mut tx = self.begin_fenced(id, token).await?;
+        self.store_in(&mut tx, id, step, state).await?;
+        commit(tx, "store commit").await
+    }
+
+    async fn store_compensation(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError> {
+        let sql = format!(
+            "INSERT INTO {} (id, step, state)
+                VALUES (?, ?, ?)
+                ON CONFLICT (id, step) DO UPDATE SET state = excluded.state",
+            self.table("compensation")
+        );
+        self.write(
+            id,
+            token,
+            sql,
+            |q| q.bind(step.to_string()).bind(state),
+            "store compensation",
+        )
+        .await
+    }
+
+    async fn store_cancelled(&self, id: Uuid, token: u64) -> Result<(), PersistError> {
+        let sql = format!(
+            "INSERT INTO {} (id, cancelled)
+                VALUES (?, true)
+                ON CONFLICT (id) DO UPDATE SET cancelled = true",
+            self.table("")
+        );
+        self.write(id, token, sql, |q| q, "store cancelled").await
+    }
+
+    async fn store_started(&self, id: Uuid, token: u64, step: StepKey) -> Result<(), PersistError> {
+        let sql = format!(
+            "INSERT INTO {} (id, step) VALUES (?, ?) ON CONFLICT DO NOTHING",
+            self.table("started")
+        );
+        self.write(
+            id,
+            token,
+            sql,
+            |q| q.bind(step.to_string()),
+            "store started",
+        )
+        .await
+    }
+
+    async fn store_attempt(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        attempt: u32,
+    ) -> Result<(), PersistError> {
+        let sql = format!(
+            "INSERT INTO {} (id, step, attempt)
+                VALUES (?, ?, ?)
+                ON CONFLICT (id, step) DO UPDATE SET attempt = excluded.attempt",
+            self.table("attempt")
+        );
+        self.write(
+            id,
+            token,
+            sql,
+            |q| q.bind(step.to_string()).bind(attempt as i64),
+            "store attempt",
+        )
+        .await
+    }
+
+    async fn store_deadline(
+        &self,
+        id: Uuid,
+        token: u64,
+        deadline: SystemTime,
+    ) -> Result<(), PersistError> {
+        let sql = format!(
+            "INSERT INTO {} (id, deadline)
+                VALUES (?, ?)
+                ON CONFLICT (id) DO UPDATE SET deadline = excluded.deadline",
+            self.table("")
+        );
+        self.write(
+            id,
+            token,
+            sql,
+            |q| q.bind(millis(deadline)),
+            "store deadline",
+        )
+        .await
+    }
+
+    async fn store_version(&self, id: Uuid, token: u64, version: u32) -> Result<(), PersistError> {
+        let mut tx = self.begin_fenced(id, token).await?;
+        self.store_version_in(&mut tx, id, version).await?;
+        commit(tx, "version commit").await
+    }
+
+    async fn store_migration(
+        &self,
+        id: Uuid,
+        token: u64,
+        version: u32,
+        states: BTreeMap<StepKey, String>,
+    ) -> Result<(), PersistError> {
+        let mut tx = self.begin_fenced(id, token).await?;
+        sqlx::query(&format!("DELETE FROM {} WHERE id = ?", self.table("step")))
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "migrate steps".to_string()))?;
+        for (step, state) in states {
+            self.store_in(&mut tx, id, step, state).await?;
+        }
+        self.store_version_in(&mut tx, id, version).await?;
+        commit(tx, "migration commit").await
+    }
+
+    async fn cancel(&self, id: Uuid) -> Result<(), PersistError> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (id, cancel_requested)
+                VALUES (?, true)
+                ON CONFLICT (id) DO UPDATE SET cancel_requested = true",
+            self.table("")
+        ))
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| PersistError::Execution(e.to_string(), "cancel".to_string()))
+    }
+
+    async fn is_cancel_requested(&self, id: Uuid) -> Result<bool, PersistError> {
+        let saga: Option<(bool,)> = sqlx::query_as(&format!(
+            "SELECT cancel_requested FROM {} WHERE id = ?",
+            self.table("")
+        ))
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve cancel".to_string()))?;
+        Ok(saga.is_some_and(|s| s.0))
+    }
+
+    async fn pause(&self, selector: SagaSelector) -> Result<(), PersistError> {
+        let table = self.table("pause");
+        let result = match selector {
+            SagaSelector::Id(id) => {
+                sqlx::query(&format!("INSERT OR IGNORE INTO {table} (id) VALUES (?)"))
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await
+            }
+            SagaSelector::Name(name) => {
+                sqlx::query(&format!("INSERT OR IGNORE INTO {table} (name) VALUES (?)"))
+                    .bind(name)
+                    .execute(&self.pool)
+                    .await
+            }
+        };
+        result
+            .map(|_| ())
+            .map_err(|e| PersistError::Execution(e.to_string(), "pause".to_string()))
+    }
+
+    async fn unpause(&self, selector: SagaSelector) -> Result<(), PersistError> {
+        let table = self.table("pause");
+        let result = match selector {
+            SagaSelector::Id(id) => {
+                sqlx::query(&format!("DELETE FROM {table} WHERE id = ?"))
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await
+            }
+            SagaSelector::Name(name) => {
+                sqlx::query(&format!("DELETE FROM {table} WHERE name = ?"))
+                    .bind(name)
+                    .execute(&self.pool)
+                    .await
+            }
+        };
+        result
+            .map(|_| ())
+            .map_err(|e| PersistError::Execution(e.to_string(), "unpause".to_string()))
+    }
+
+    async fn is_paused(&self, id: Uuid, name: &str) -> Result<bool, PersistError> {
+        let (paused,): (bool,) = sqlx::query_as(&format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE id = ? OR name = ?)",
+            self.table("pause")
+        ))
+        .bind(id)
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve pause".to_string()))?;
+        Ok(paused)
+    }
+
+    async fn requested_interruptions(
+        &self,
+        id: Uuid,
+        name: &str,
+    ) -> Result<(bool, bool), PersistError> {
+        sqlx::query_as(&format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE id = ? AND cancel_requested),
+                EXISTS (SELECT 1 FROM {} WHERE id = ? OR name = ?)",
+            self.table(""),
+            self.table("pause")
+        ))
+        .bind(id)
+        .bind(id)
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve interruptions".to_string()))
+    }
+
+    async fn signal(&self, id: Uuid, name: &str, payload: String) -> Result<(), PersistError> {
+        let mut tx = self.begin("signal transaction").await?;
+        sqlx::query(&format!(
+            "INSERT INTO {} (id, name, payload)
+                VALUES (?, ?, ?)
+                ON CONFLICT (id, name) DO UPDATE SET payload = excluded.payload",
+            self.table("signal")
+        ))
+        .bind(id)
+        .bind(name)
+        .bind(payload)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "store signal".to_string()))?;
+        // parents parked by the waiting child are woken up with it
+        let mut next = Some(id);
+        while let Some(saga) = next {
+            sqlx::query(&format!(
+                "INSERT INTO {} (id, signalled)
+                    VALUES (?, true)
+                    ON CONFLICT (id) DO UPDATE SET signalled = true",
+                self.table("")
+            ))
+            .bind(saga)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "store signalled".to_string()))?;
+            next = sqlx::query_as::<_, (Uuid,)>(&format!(
+                "SELECT parent_id FROM {} WHERE id = ? AND parent_id IS NOT NULL LIMIT 1",
+                self.table("lock")
+            ))
+            .bind(saga)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| PersistError::Execution(e.to_string(), "retrieve parent".to_string()))?
+            .map(|p| p.0);
+        }
+        commit(tx, "signal commit").await
+    }
+
+    async fn store_consumed_signal(
+        &self,
+        id: Uuid,
+        token: u64,
+        step: StepKey,
+        state: String,
+        name: &str,
+    ) -> Result<(), PersistError> {
+        let mut tx = self.begin_fenced(id, token).await?;
+        self.store_in(&mut tx, id, step, state).await?;
+        sqlx::query(&format!(
+            "DELETE FROM {} WHERE id = ? AND name = ?",
+            self.table("signal")
+        ))
+        .bind(id)
+        .bind(name)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "consume signal".to_string()))?;
+        commit(tx, "signal step commit").await
+    }
+
+    async fn store_schedule(&self, schedule: Schedule) -> Result<(), PersistError> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (id, name, data, cron, next_at)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT (id) DO UPDATE SET name = excluded.name, data = excluded.data,
+                    cron = excluded.cron, next_at = excluded.next_at",
+            self.table("schedule")
+        ))
+        .bind(schedule.id)
+        .bind(schedule.name)
+        .bind(schedule.data)
+        .bind(schedule.cron)
+        .bind(millis(schedule.next_at))
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| PersistError::Execution(e.to_string(), "store schedule".to_string()))
+    }
+
+    async fn remove_schedule(&self, id: Uuid) -> Result<(), PersistError> {
+        sqlx::query(&format!(
+            "DELETE FROM {} WHERE id = ?",
+            self.table("schedule")
+        ))
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| PersistError::Execution(e.to_string(), "remove schedule".to_string()))
+    }
+
+    async fn get_due_schedules(&self, now: SystemTime) -> Result<Vec<Schedule>, PersistError> {
+        let rows: Vec<(Uuid, String, String, Option<String>, i64)> = sqlx::query_as(&format!(
+            "SELECT id, name, data, cron, next_at FROM {} WHERE next_at <= ?",
+            self.table("schedule")
+        ))
+        .bind(millis(now))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve schedules".to_string()))?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, name, data, cron, next_at)| Schedule {
+                id,
+                name,
+                data,
+                cron,
+                next_at: from_millis(next_at),
+            })
+            .collect())
+    }
+
+    async fn start_occurrence(
+        &self,
+        schedule: Schedule,
+        next_at: Option<SystemTime>,
+    ) -> Result<Uuid, PersistError> {
+        let table = self.table("schedule");
+        // the schedule row stays locked until the occurrence is created, so it starts once
+        let mut tx = self.begin("schedule transaction").await?;
+        let result = match next_at {
+            Some(next_at) => {
+                sqlx::query(&format!(
+                    "UPDATE {table} SET next_at = ? WHERE id = ? AND next_at = ?"
+                ))
+                .bind(millis(next_at))
+                .bind(schedule.id)
+                .bind(millis(schedule.next_at))
+                .execute(&mut *tx)
+                .await
+            }
+            None => {
+                sqlx::query(&format!("DELETE FROM {table} WHERE id = ? AND next_at = ?"))
+                    .bind(schedule.id)
+                    .bind(millis(schedule.next_at))
+                    .execute(&mut *tx)
+                    .await
+            }
+        }
+        .map_err(|e| PersistError::Execution(e.to_string(), "advance schedule".to_string()))?;
+        if result.rows_affected() == 0 {
+            return Err(PersistError::Locked);
+        }
+
+        let scope = LockScope::from_id(schedule.occurrence_id(schedule.next_at), schedule.name);
+        let token = self
+            .lock_in(&mut tx, scope.clone(), LockType::Initial)
+            .await?;
+        self.store_in(&mut tx, scope.id, StepKey::Index(0), schedule.data)
+            .await?;
+        self.lock_in(
+            &mut tx,
+            LockScope {
+                token: Some(token),
+                ..scope.clone()
+            },
+            LockType::Failed,
+        )
+        .await?;
+        commit(tx, "schedule commit").await?;
+        Ok(scope.id)
+    }
+
+    async fn get_next_failed(
+        &self,
+        for_duration: Duration,
+    ) -> Result<Option<(Uuid, String, Uuid)>, PersistError> {
+        let mut tx = self.begin_write("get transaction").await?;
+        let now = millis(SystemTime::now());
+        // only the latest lock of every saga counts, a paused child keeps its parent paused
+        let result: Option<(Uuid, String)> = sqlx::query_as(&format!(
+            "SELECT l.id, l.name FROM {lock} l
+                LEFT JOIN {saga} s ON s.id = l.id
+                WHERE l.token = (SELECT MAX(token) FROM {lock} WHERE id = l.id)
+                    AND l.parent_id IS NULL
+                    AND (l.lock IN (?2, ?5)
+                        OR (l.lock = ?6 AND s.signalled)
+                        OR (l.lock = ?7 AND l.wake_at <= ?8)
+                        OR (l.dtc < ?1 AND l.lock NOT IN (?3, ?4, ?6, ?7)))
+                    AND NOT EXISTS (SELECT 1 FROM {pause} p WHERE p.id = l.id OR p.name = l.name)
+                    AND NOT EXISTS (SELECT 1 FROM {lock} c JOIN {pause} p ON p.id = c.id OR p.name = c.name
+                        WHERE c.parent_id = l.id)
+                ORDER BY l.dtc DESC LIMIT 1",
+            lock = self.table("lock"),
+            saga = self.table(""),
+            pause = self.table("pause"),
+        ))
+        .bind(now - for_duration.as_millis() as i64)
+        .bind(FAILED)
+        .bind(FINISHED)
+        .bind(CANCELLED)
+        .bind(PAUSED)
+        .bind(WAITING)
+        .bind(SLEEPING)
+        .bind(now)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), "retrieve failed".to_string()))?;
+
+        let Some((id, name)) = result else {
+            return Ok(None);
+        };
+        let executor_id = Uuid::new_v4();
+        let scope = LockScope {
+            id,
+            executor_id,
+            name,
+            parent: None,
+            token: None,
+        };
+        self.lock_in(&mut tx, scope.clone(), LockType::Retry)
+            .await?;
+        commit(tx, "get commit").await?;
+        Ok(Some((scope.id, scope.name, executor_id)))
+    }
+}
+
+#[async_trait::async_trait]
+impl TransactionalPersister for SqlitePersister {
+    type Transaction = Transaction<'static, Sqlite>;
+
+    async fn begin(
+        &self,
+        id: Uuid,
+        token: u64,
+    ) -> Result<Transaction<'static, Sqlite>, PersistError> {
+        self.begin_fenced(id, token).await
+    }
+
+    async fn commit_step(
+        &self,
+        mut transaction: Transaction<'static, Sqlite>,
+        id: Uuid,
+        step: StepKey,
+        state: String,
+    ) -> Result<(), PersistError> {
+        self.store_in(&mut transaction, id, step, state).await?;
+        commit(transaction, "step commit").await
+    }
+}
+
+const EXECUTING: &str = "Executing";
+const FAILED: &str = "Failed";
+const FINISHED: &str = "Finished";
+const INITIAL: &str = "Initial";
+const RETRY: &str = "Retry";
+const PAUSED: &str = "Paused";
+const WAITING: &str = "Waiting";
+const SLEEPING: &str = "Sleeping";
+const CANCELLED: &str = "Cancelled";
+
+fn lock_name(lock_type: &LockType) -> &'static str {
+    match lock_type {
+        LockType::Executing => EXECUTING,
+        LockType::Failed => FAILED,
+        LockType::Finished => FINISHED,
+        LockType::Initial => INITIAL,
+        LockType::Retry => RETRY,
+        LockType::Paused => PAUSED,
+        LockType::Waiting => WAITING,
+        LockType::Sleeping(_) => SLEEPING,
+        LockType::Cancelled => CANCELLED,
+    }
+}
+
+// identifiers are quoted, quotes within them are doubled
+fn escape(identifier: &str) -> String {
+    identifier.replace('"', "\"\"")
+}
+
+// times are stored as milliseconds since the unix epoch
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn from_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+async fn commit(tx: Transaction<'_, Sqlite>, context: &str) -> Result<(), PersistError> {
+    tx.commit()
+        .await
+        .map_err(|e| PersistError::Execution(e.to_string(), context.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+    use crate::definitions::saga_definition::{SagaDefinition, SagaRunner};
+
+    use super::*;
+
+    // file in the temp dir, an in-memory database is private to a single connection
+    async fn persister(lock_timeout: Duration) -> SqlitePersister {
+        let path = std::env::temp_dir().join(format!("transaction_state_{}.db", Uuid::new_v4()));
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(
+                SqliteConnectOptions::new()
+                    .filename(path)
+                    .create_if_missing(true),
+            )
+            .await
+            .unwrap();
+        let persister = SqlitePersister::new(pool, lock_timeout);
+        persister.migrate().await.unwrap();
+        persister
+    }
+
+    #[tokio::test]
+    async fn test_same_executor_can_always_lock() {
+        let persister = persister(Duration::from_millis(10)).await;
+        let scope = LockScope::from_id(Uuid::new_v4(), "test1".to_string());
+        for lock_type in [
+            LockType::Initial,
+            LockType::Failed,
+            LockType::Retry,
+            LockType::Executing,
+            LockType::Finished,
+        ] {
+            persister.lock(scope.clone(), lock_type).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_different_executor_can_lock_conditionally() {
+        let persister = persister(Duration::from_millis(100)).await;
+        let scope1 = LockScope::from_id(Uuid::new_v4(), "test1".to_string());
+        let scope2 = LockScope::from_id(scope1.id, "test1".to_string());
+        persister
+            .lock(scope1.clone(), LockType::Initial)
+            .await
+            .unwrap();
+
+        let result = persister.lock(scope2.clone(), LockType::Executing).await;
+        assert!(matches!(result, Err(PersistError::Locked)), "{result:?}");
+
+        sleep(Duration::from_millis(113));
+
+        let result = persister.lock(scope2.clone(), LockType::Failed).await;
+        assert!(result.is_ok(), "{result:?}");
+
+        let result = persister.lock(scope1.clone(), LockType::Executing).await;
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_executors_lock_once() {
+        let persister = persister(Duration::from_secs(5)).await;
+        let id = Uuid::new_v4();
+        let locks = (0..5).map(|_| {
+            let persister = persister.clone();
+            async move {
+                persister
+                    .lock(
+                        LockScope::from_id(id, "test1".to_string()),
+                        LockType::Initial,
+                    )
+                    .await
+            }
+        });
+        let results = futures_util::future::join_all(locks).await;
+        assert_eq!(
+            1,
+            results.iter().filter(|r| r.is_ok()).count(),
+            "{results:?}"
+        );
+        assert!(results
+            .iter()
+            .all(|r| matches!(r, Ok(_) | Err(PersistError::Locked))));
+    }
+
+    #[tokio::test]
+    async fn test_initial_state_commits_with_the_transaction() {
+        let persister = persister(Duration::from_secs(5)).await;
+        let scope = LockScope::from_id(Uuid::new_v4(), "test1".to_string());
+        let mut tx = persister.pool.begin().await.unwrap();
+        let token = persister
+            .save_initial_state(&mut tx, scope.clone(), &7)
+            .await
+            .unwrap();
+        tx.rollback().await.unwrap();
+        assert!(matches!(
+            persister.begin_fenced(scope.id, token).await,
+            Err(PersistError::Fenced)
+        ));
+
+        let mut tx = persister.pool.begin().await.unwrap();
+        persister
+            .save_initial_state(&mut tx, scope.clone(), &7)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        let saga = persister.retrieve(scope.id).await.unwrap();
+        assert_eq!(Some(&"7".to_string()), saga.states.get(&StepKey::Index(0)));
+    }
+
+    fn create_definition(
+        lock_scope: LockScope,
+        fail: bool,
+        persister: SqlitePersister,
+    ) -> SagaDefinition<u32, u32, u32, PersistError, SqlitePersister> {
+        SagaDefinition::new(lock_scope, |v: u32, _: &()| v, (), persister)
+            .transactional_step(
+                |v: u32, tx| async move { Ok::<_, PersistError>((v + 1, tx)) },
+                |v, _| *v,
+            )
+            .step(
+                move |v: u32| async move {
+                    if fail {
+                        Err(PersistError::Timeout)
+                    } else {
+                        Ok(v * 2)
+                    }
+                },
+                |_, v| v,
+            )
+    }
+
+    #[tokio::test]
+    async fn test_failed_saga_is_resumed() {
+        let persister = persister(Duration::from_secs(5)).await;
+        let scope = LockScope::from_id(Uuid::new_v4(), "resumed".to_string());
+        let result = create_definition(scope.clone(), true, persister.clone())
+            .run(7)
+            .await;
+        assert!(matches!(result, Err(PersistError::Timeout)), "{result:?}");
+        let saga = persister.retrieve(scope.id).await.unwrap();
+        assert_eq!(Some(&"8".to_string()), saga.states.get(&StepKey::Index(1)));
+
+        let (id, name, executor_id) = persister
+            .get_next_failed(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((scope.id, "resumed".to_string()), (id, name.clone()));
+        assert!(persister
+            .get_next_failed(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .is_none());
+        let scope = LockScope {
+            executor_id,
+            ..LockScope::from_id(id, name)
+        };
+        let result = create_definition(scope, false, persister.clone())
+            .continue_from_last_step()
+            .await;
+        assert_eq!(16, result.unwrap());
+        assert!(matches!(
+            persister.retrieve(id).await.map(|s| s.states.is_empty()),
+            Ok(true)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_signalled_child_wakes_parent() {
+        let persister = persister(Duration::from_secs(5)).await;
+        let parent = LockScope::from_id(Uuid::new_v4(), "parent".to_string());
+        let child = parent.child(&StepKey::Index(1));
+        persister
+            .lock(child.clone(), LockType::Waiting)
+            .await
+            .unwrap();
+        persister
+            .lock(parent.clone(), LockType::Waiting)
+            .await
+            .unwrap();
+        persister.pause(SagaSelector::Id(child.id)).await.unwrap();
+        persister
+            .signal(child.id, "approval", "true".to_string())
+            .await
+            .unwrap();
+        // a paused child keeps its parent paused
+        assert!(persister
+            .get_next_failed(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .is_none());
+
+        persister.unpause(SagaSelector::Id(child.id)).await.unwrap();
+        let next = persister
+            .get_next_failed(Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(Some(parent.id), next.map(|(id, _, _)| id));
+    }
+
+    #[tokio::test]
+    async fn test_failed_occurrence_start_keeps_schedule_due() {
+        let persister = persister(Duration::from_secs(5)).await;
+        let at = UNIX_EPOCH + Duration::from_secs(60);
+        let schedule = Schedule::at(Uuid::new_v4(), "test", &7, at).unwrap();
+        persister.store_schedule(schedule.clone()).await.unwrap();
+        let occurrence = LockScope::from_id(schedule.occurrence_id(at), "test".to_string());
+        persister
+            .lock(occurrence.clone(), LockType::Initial)
+            .await
+            .unwrap();
+        persister
+            .lock(occurrence.clone(), LockType::Cancelled)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            persister.start_occurrence(schedule.clone(), None).await,
+            Err(PersistError::Cancelled)
+        ));
+        // nothing of the occurrence is written, the schedule is still at it
+        assert_eq!(
+            vec![schedule.id],
+            persister
+                .get_due_schedules(at)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>()
+        );
+        let saga = persister.retrieve(occurrence.id).await.unwrap();
+        assert!(saga.states.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_interruptions_are_read_at_once() {
+        let persister = persister(Duration::from_secs(5)).await;
+        let scope = LockScope::from_id(Uuid::new_v4(), "interrupted".to_string());
+        persister
+            .lock(scope.clone(), LockType::Initial)
+            .await
+            .unwrap();
+        assert_eq!(
+            (false, false),
+            persister
+                .requested_interruptions(scope.id, &scope.name)
+                .await
+                .unwrap()
+        );
+
+        persister.cancel(scope.id).await.unwrap();
+        persister
+            .pause(SagaSelector::Name(scope.name.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            (true, true),
+            persister
+                .requested_interruptions(scope.id, &scope.name)
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::{sync::Semaphore, time::sleep};
+use uuid::Uuid;
+
+use crate::{
+    definitions::{
+        saga_registry::{RegistryError, SagaRegistry},
+        schedule::start_due,
+    },
+    persisters::persister::{PersistError, StepPersister},
+};
+
+pub type ErrorReporter<E> = Arc<dyn Fn(WorkerError<E>) + Send + Sync>;
+
+#[derive(Debug)]
+pub enum WorkerError<E> {
+    /// Claiming the next failed saga or retrieving the due schedules failed
+    Persist(PersistError),
+    /// Starting the scheduled saga failed
+    Schedule(Uuid, PersistError),
+    /// No definition is registered under the name of the claimed saga
+    UnknownDefinition(Uuid, String),
+    /// Resumed saga failed again
+    Saga(Uuid, String, E),
+}
+
+impl<E: fmt::Display> fmt::Display for WorkerError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Persist(e) => write!(f, "Failed to claim saga: {e}"),
+            Self::Schedule(id, e) => write!(f, "Failed to start schedule {id}: {e}"),
+            Self::UnknownDefinition(id, name) => {
+                write!(f, "No definition registered to resume saga {name} {id}")
+            }
+            Self::Saga(id, name, e) => write!(f, "Resumed saga {name} {id} failed: {e}"),
+        }
+    }
+}
+
+/// Resumes failed sagas with the definitions of the registry
+pub struct ResumeWorker<Persister, WrappingError> {
+    registry: Arc<SagaRegistry<WrappingError>>,
+    persister: Persister,
+    poll_interval: Duration,
+    restart_after: Duration,
+    max_concurrent: usize,
+    on_error: ErrorReporter<WrappingError>,
+}
+
+impl<Persister, WrappingError> ResumeWorker<Persister, WrappingError>
+where
+    Persister: StepPersister,
+    WrappingError: Error + From<PersistError> + Send + 'static,
+{
+    pub fn new(registry: Arc<SagaRegistry<WrappingError>>, persister: Persister) -> Self {
+        Self {
+            registry,
+            persister,
+            poll_interval: Duration::from_secs(1),
+            restart_after: Duration::from_secs(60),
+            max_concurrent: 10,
+            on_error: Arc::new(|e| log::error!("{e}")),
+        }
+    }
+
+    /// How long to wait before polling again when there is nothing to resume
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Sagas locked for longer than the duration are considered failed
+    pub fn restart_after(mut self, duration: Duration) -> Self {
+        self.restart_after = duration;
+        self
+    }
+
+    /// Maximum number of sagas resumed at once
+    pub fn max_concurrent(mut self, limit: usize) -> Self {
+        self.max_concurrent = limit.max(1);
+        self
+    }
+
+    /// Errors are logged by default
+    pub fn on_error(
+        mut self,
+        reporter: impl Fn(WorkerError<WrappingError>) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_error = Arc::new(reporter);
+        self
+    }
+
+    /// Resumes failed sagas until shutdown completes, then waits for sagas being resumed.
+    /// Due schedules are started at most once per poll interval and resumed like failed sagas.
+    ///
+    /// Returns the number of resumed sagas.
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> u64 {
+        let slots = Arc::new(Semaphore::new(self.max_concurrent));
+        let mut resumed = 0;
+        let mut schedules_started: Option<Instant> = None;
+        tokio::pin!(shutdown);
+        loop {
+            if schedules_started.is_none_or(|i| i.elapsed() >= self.poll_interval) {
+                schedules_started = Some(Instant::now());
+                match start_due(&self.persister, SystemTime::now()).await {
+                    Ok(results) => {
+                        for (schedule_id, result) in results {
+                            if let Err(e) = result {
+                                (self.on_error)(WorkerError::Schedule(schedule_id, e));
+                            }
+                        }
+                    }
+                    Err(e) => (self.on_error)(WorkerError::Persist(e)),
+                }
+            }
+
+            let slot = tokio::select! {
+                _ = &mut shutdown => break,
+                slot = slots.clone().acquire_owned() => slot.expect("worker slots are never closed"),
+            };
+
+            match self.persister.get_next_failed(self.restart_after).await {
+                Ok(Some((id, name, executor_id))) => {
+                    resumed += 1;
+                    let registry = self.registry.clone();
+                    let on_error = self.on_error.clone();
+                    tokio::spawn(async move {
+                        log::trace!("resuming {name} {id}");
+                        match registry.resume(id, &name, executor_id).await {
+                            Ok(_) => {}
+                            Err(RegistryError::UnknownDefinition(_)) => {
+                                on_error(WorkerError::UnknownDefinition(id, name))
+                            }
+                            Err(RegistryError::Saga(e)) => on_error(WorkerError::Saga(id, name, e)),
+                        }
+                        drop(slot);
+                    });
+                    continue;
+                }
+                // another worker claimed the saga first
+                Ok(None) | Err(PersistError::NotFound | PersistError::Locked) => {}
+                Err(e) => (self.on_error)(WorkerError::Persist(e)),
+            }
+
+            drop(slot);
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = sleep(self.poll_interval) => {}
+            }
+        }
+
+        // every slot is released once the sagas being resumed finish
+        let _ = slots.acquire_many(self.max_concurrent as u32).await;
+        resumed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        RwLock,
+    };
+
+    use crate::{
+        definitions::{saga_definition::SagaDefinition, schedule::Schedule, step_key::StepKey},
+        persisters::{
+            in_memory::InMemoryPersister,
+            persister::{LockScope, LockType},
+        },
+    };
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct WorkerTestError(String);
+
+    impl fmt::Display for WorkerTestError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl Error for WorkerTestError {}
+
+    impl From<PersistError> for WorkerTestError {
+        fn from(value: PersistError) -> Self {
+            Self(value.to_string())
+        }
+    }
+
+    async fn store_failed(persister: &InMemoryPersister, name: &str) -> Uuid {
+        let scope = LockScope::from_id(Uuid::new_v4(), name.to_string());
+        let token = persister
+            .lock(scope.clone(), LockType::Initial)
+            .await
+            .unwrap();
+        persister
+            .store(scope.id, token, StepKey::Index(0), "1".to_string())
+            .await
+            .unwrap();
+        persister
+            .lock(scope.clone(), LockType::Failed)
+            .await
+            .unwrap();
+        scope.id
+    }
+
+    #[tokio::test]
+    async fn test_worker_resumes_failed_sagas() {
+        let persister = InMemoryPersister::new(Duration::from_secs(5));
+        let executed = Arc::new(AtomicU32::new(0));
+        let counter = executed.clone();
+        let definition_persister = persister.clone();
+        let registry = SagaRegistry::<WorkerTestError>::new().register("count", move |scope| {
+            let counter = counter.clone();
+            SagaDefinition::new(scope, |_: u32, _: &()| (), (), definition_persister.clone()).step(
+                move |v: u32| async move {
+                    sleep(Duration::from_millis(100)).await;
+                    counter.fetch_add(v, Ordering::SeqCst);
+                    Ok::<_, WorkerTestError>(v)
+                },
+                |_, _| 1,
+            )
+        });
+        for _ in 0..3 {
+            store_failed(&persister, "count").await;
+        }
+
+        let resumed = ResumeWorker::new(Arc::new(registry), persister)
+            .poll_interval(Duration::from_millis(5))
+            .max_concurrent(2)
+            .run(sleep(Duration::from_millis(30)))
+            .await;
+        // shutdown waits for the sagas already being resumed
+        assert_eq!(2, resumed);
+        assert_eq!(2, executed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_worker_reports_errors() {
+        let persister = InMemoryPersister::new(Duration::from_secs(5));
+        let id = store_failed(&persister, "unknown").await;
+        let errors = Arc::new(RwLock::new(Vec::new()));
+        let reported = errors.clone();
+
+        let resumed =
+            ResumeWorker::new(Arc::new(SagaRegistry::<WorkerTestError>::new()), persister)
+                .poll_interval(Duration::from_millis(5))
+                .on_error(move |e| reported.write().unwrap().push(e.to_string()))
+                .run(sleep(Duration::from_millis(20)))
+                .await;
+        assert_eq!(1, resumed);
+        assert_eq!(
+            vec![format!(
+                "No definition registered to resume saga unknown {id}"
+            )],
+            *errors.read().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_worker_does_not_report_parked_sagas() {
+        let persister = InMemoryPersister::new(Duration::from_secs(5));
+        let definition_persister = persister.clone();
+        let registry = SagaRegistry::<WorkerTestError>::new().register("wait", move |scope| {
+            SagaDefinition::new(scope, |_: u32, _: &()| (), (), definition_persister.clone())
+                .wait_for_signal::<u32>("approval")
+        });
+        let id = store_failed(&persister, "wait").await;
+        let errors = Arc::new(RwLock::new(Vec::new()));
+        let reported = errors.clone();
+
+        let resumed = ResumeWorker::new(Arc::new(registry), persister.clone())
+            .poll_interval(Duration::from_millis(5))
+            .on_error(move |e| reported.write().unwrap().push(e.to_string()))
+            .run(sleep(Duration::from_millis(20)))
+            .await;
+        assert_eq!(1, resumed);
+        assert!(errors.read().unwrap().is_empty());
+        assert!(persister.retrieve(id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_worker_starts_due_schedules() {
+        let persister = InMemoryPersister::new(Duration::from_secs(5));
+        let executed = Arc::new(AtomicU32::new(0));
+        let counter = executed.clone();
+        let definition_persister = persister.clone();
+        let registry = SagaRegistry::<WorkerTestError>::new().register("count", move |scope| {
+            let counter = counter.clone();
+            SagaDefinition::new(scope, |v: u32, _: &()| v, (), definition_persister.clone()).step(
+                move |v: u32| async move {
+                    counter.fetch_add(v, Ordering::SeqCst);
+                    Ok::<_, WorkerTestError>(v)
+                },
+                |v, _| *v,
+            )
+        });
+        let schedule = Schedule::at(Uuid::new_v4(), "count", &3, SystemTime::now()).unwrap();
+        persister.store_schedule(schedule).await.unwrap();
+
+        let registry = Arc::new(registry);
+        let (first, second) = tokio::join!(
+            ResumeWorker::new(registry.clone(), persister.clone())
+                .poll_interval(Duration::from_millis(5))
+                .run(sleep(Duration::from_millis(30))),
+            ResumeWorker::new(registry, persister.clone())
+                .poll_interval(Duration::from_millis(5))
+                .run(sleep(Duration::from_millis(30)))
+        );
+        assert_eq!(1, first + second);
+        assert_eq!(3, executed.load(Ordering::SeqCst));
+        assert!(persister
+            .get_due_schedules(SystemTime::now())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}